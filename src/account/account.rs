@@ -5,9 +5,41 @@ use super::address::Address;
 pub type PublicKey = [u8; 897];
 pub type SecretKey = [u8; 1281];
 
+/// On-chain record of an address.
+///
+/// `pkey` is only known once the owner has revealed it in a transaction.
 #[derive(PartialEq, Clone, Copy, Debug, Encode, Decode)]
 pub struct Account {
-    address: Address,
-    pkey: Option<PublicKey>,
-    balance: u64,
+    pub(crate) address: Address,
+    pub(crate) pkey: Option<PublicKey>,
+    pub(crate) balance: u64,
+    pub(crate) nonce: u64,
+}
+
+impl Account {
+    pub fn new(address: Address) -> Self {
+        Self {
+            address,
+            pkey: None,
+            balance: 0,
+            nonce: 0,
+        }
+    }
+
+    pub fn address(&self) -> Address {
+        self.address
+    }
+
+    pub fn public_key(&self) -> Option<&PublicKey> {
+        self.pkey.as_ref()
+    }
+
+    pub fn balance(&self) -> u64 {
+        self.balance
+    }
+
+    /// Nonce the next transaction sent from this account must carry.
+    pub fn nonce(&self) -> u64 {
+        self.nonce
+    }
 }
@@ -1,7 +1,9 @@
 mod account;
 mod address;
 mod error;
+mod state;
 
-pub use account::{PublicKey, SecretKey};
+pub use account::{Account, PublicKey, SecretKey};
 pub use address::Address;
 pub use error::AddressParseError;
+pub use state::{AccountProof, NodeKey, StateNode, StateTree, state_root};
@@ -0,0 +1,422 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+
+use bincode::{Decode, Encode, config};
+use sha3::{Digest, Sha3_256};
+
+use crate::block::{BlockHash, hash_leaf, hash_node};
+
+use super::account::Account;
+use super::address::Address;
+
+const EMPTY: BlockHash = BlockHash::ZERO;
+
+/// Position of a node in a [`StateTree`]: its depth and the turns taken from
+/// the root to reach it, as an address whose bits past `depth` are zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Encode, Decode)]
+pub struct NodeKey {
+    depth: u16,
+    prefix: Address,
+}
+
+/// Node of a [`StateTree`] holding at least one account. Empty subtrees have
+/// no node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+pub enum StateNode {
+    /// Subtree holding a single account, as its address and record hash.
+    Leaf(Address, BlockHash),
+    /// Subtree holding several accounts, as the roots of its two halves.
+    Inner(BlockHash, BlockHash),
+}
+
+/// Sparse Merkle tree over account records, keyed by address.
+///
+/// The bits of an address, most significant first, are the left and right
+/// turns from the root down to its leaf. A subtree without accounts hashes to
+/// all zeros and a subtree with a single account hashes to that account's
+/// leaf, so the tree is only as deep as it takes to separate addresses. Leaves
+/// commit to the full address, so one can never be passed off as another at a
+/// different position.
+///
+/// Nodes are read through `nodes` and changes are kept aside until taken with
+/// [`StateTree::into_changes`], so that updating an account only touches the
+/// nodes on its path.
+#[derive(Debug, Clone)]
+pub struct StateTree<F> {
+    nodes: F,
+    changes: HashMap<NodeKey, Option<StateNode>>,
+}
+
+/// Path from the root of a [`StateTree`] down to the subtree an address falls
+/// in, proving either the record of that address or that it has none.
+#[derive(Debug, Clone, PartialEq, Encode, Decode)]
+pub struct AccountProof {
+    /// Only account in the subtree, as its address and record hash, or `None`
+    /// if the subtree is empty.
+    pub leaf: Option<(Address, BlockHash)>,
+    /// Roots of the sibling subtrees, from the top of the tree down.
+    pub siblings: Vec<BlockHash>,
+}
+
+impl NodeKey {
+    fn root() -> Self {
+        Self::of(&Address::from([0u8; 32]), 0)
+    }
+
+    /// Position at `depth` on the path to `address`.
+    fn of(address: &Address, depth: u16) -> Self {
+        let mut prefix = [0u8; 32];
+        for (i, byte) in prefix.iter_mut().enumerate() {
+            let bits = (depth as usize).saturating_sub(i * 8).min(8);
+            *byte = address.as_ref()[i] & !(0xffu8.checked_shr(bits as u32).unwrap_or(0));
+        }
+
+        Self {
+            depth,
+            prefix: Address::from(prefix),
+        }
+    }
+
+    fn child(&self, right: bool) -> Self {
+        let mut prefix: [u8; 32] = self.prefix.as_ref().try_into().unwrap();
+        if right {
+            prefix[self.depth as usize / 8] |= 0x80 >> (self.depth % 8);
+        }
+
+        Self {
+            depth: self.depth + 1,
+            prefix: Address::from(prefix),
+        }
+    }
+}
+
+impl StateNode {
+    pub fn hash(&self) -> BlockHash {
+        match self {
+            Self::Leaf(address, hash) => account_leaf(address, hash),
+            Self::Inner(left, right) => hash_node(left, right),
+        }
+    }
+}
+
+impl<F, E> StateTree<F>
+where
+    F: Fn(&NodeKey) -> Result<Option<StateNode>, E>,
+{
+    /// Tree whose nodes are read with `nodes`.
+    pub fn new(nodes: F) -> Self {
+        Self {
+            nodes,
+            changes: HashMap::new(),
+        }
+    }
+
+    pub fn root(&self) -> Result<BlockHash, E> {
+        self.hash_at(&NodeKey::root())
+    }
+
+    /// Sets the record of the address of `account`.
+    pub fn insert(&mut self, account: &Account) -> Result<(), E> {
+        self.update(&account.address, Some(hash_account(account)))
+    }
+
+    /// Removes the record of `address`, if it has one.
+    pub fn remove(&mut self, address: &Address) -> Result<(), E> {
+        self.update(address, None)
+    }
+
+    /// Inclusion proof for the record of `address`, or exclusion proof if it
+    /// has none.
+    pub fn proof(&self, address: &Address) -> Result<AccountProof, E> {
+        let mut key = NodeKey::root();
+        let mut siblings = Vec::new();
+
+        loop {
+            match self.node(&key)? {
+                Some(StateNode::Inner(left, right)) => {
+                    let right_turn = bit(address, key.depth as usize);
+                    siblings.push(if right_turn { left } else { right });
+                    key = key.child(right_turn);
+                }
+                Some(StateNode::Leaf(leaf_address, hash)) => {
+                    return Ok(AccountProof {
+                        leaf: Some((leaf_address, hash)),
+                        siblings,
+                    });
+                }
+                None => {
+                    return Ok(AccountProof {
+                        leaf: None,
+                        siblings,
+                    });
+                }
+            }
+        }
+    }
+
+    /// Nodes written or removed since the tree was created.
+    pub fn into_changes(self) -> HashMap<NodeKey, Option<StateNode>> {
+        self.changes
+    }
+
+    fn node(&self, key: &NodeKey) -> Result<Option<StateNode>, E> {
+        match self.changes.get(key) {
+            Some(node) => Ok(*node),
+            None => (self.nodes)(key),
+        }
+    }
+
+    fn hash_at(&self, key: &NodeKey) -> Result<BlockHash, E> {
+        Ok(self.node(key)?.map_or(EMPTY, |node| node.hash()))
+    }
+
+    fn set(&mut self, key: NodeKey, node: Option<StateNode>) {
+        self.changes.insert(key, node);
+    }
+
+    /// Sets the record hash of `address`, or removes it if `leaf` is `None`,
+    /// then rehashes the inner nodes on its path.
+    fn update(&mut self, address: &Address, leaf: Option<BlockHash>) -> Result<(), E> {
+        let mut path = Vec::new();
+        let mut key = NodeKey::root();
+        let found = loop {
+            match self.node(&key)? {
+                Some(StateNode::Inner(..)) => {
+                    path.push(key);
+                    key = key.child(bit(address, key.depth as usize));
+                }
+                found => break found,
+            }
+        };
+
+        match (found, leaf) {
+            (None, None) => return Ok(()),
+            (Some(StateNode::Leaf(other, _)), None) if other != *address => return Ok(()),
+            (Some(StateNode::Leaf(other, other_hash)), Some(hash)) if other != *address => {
+                // Both accounts share the subtree until their addresses differ.
+                self.set(key, None);
+                let mut depth = key.depth;
+                while bit(&other, depth as usize) == bit(address, depth as usize) {
+                    path.push(NodeKey::of(address, depth));
+                    depth += 1;
+                }
+                path.push(NodeKey::of(address, depth));
+                self.set(
+                    NodeKey::of(&other, depth + 1),
+                    Some(StateNode::Leaf(other, other_hash)),
+                );
+                self.set(
+                    NodeKey::of(address, depth + 1),
+                    Some(StateNode::Leaf(*address, hash)),
+                );
+            }
+            (_, Some(hash)) => self.set(key, Some(StateNode::Leaf(*address, hash))),
+            (_, None) => {
+                self.set(key, None);
+                self.collapse(address, &mut path)?;
+            }
+        }
+
+        for key in path.into_iter().rev() {
+            let left = self.hash_at(&key.child(false))?;
+            let right = self.hash_at(&key.child(true))?;
+            self.set(key, Some(StateNode::Inner(left, right)));
+        }
+
+        Ok(())
+    }
+
+    /// Moves a leaf left alone in its subtree by a removal on the path to
+    /// `address` up for as long as it has no sibling.
+    fn collapse(&mut self, address: &Address, path: &mut Vec<NodeKey>) -> Result<(), E> {
+        while let Some(parent) = path.last().copied() {
+            let near = parent.child(bit(address, parent.depth as usize));
+            let far = parent.child(!bit(address, parent.depth as usize));
+            let (from, leaf) = match (self.node(&near)?, self.node(&far)?) {
+                (Some(leaf @ StateNode::Leaf(..)), None) => (near, leaf),
+                (None, Some(leaf @ StateNode::Leaf(..))) => (far, leaf),
+                _ => break,
+            };
+
+            self.set(from, None);
+            self.set(parent, Some(leaf));
+            path.pop();
+        }
+
+        Ok(())
+    }
+}
+
+impl AccountProof {
+    /// Checks that `account` is the record of `address` in the tree whose
+    /// root is `root`, or if `account` is `None`, that the tree holds no
+    /// record for `address`.
+    pub fn verify(&self, address: &Address, account: Option<&Account>, root: &BlockHash) -> bool {
+        if self.siblings.len() > 256 {
+            return false;
+        }
+
+        let valid_leaf = match (account, &self.leaf) {
+            (Some(account), Some((leaf_address, hash))) => {
+                leaf_address == address
+                    && account.address == *address
+                    && *hash == hash_account(account)
+            }
+            // Another address can only end the path if it shares every turn
+            // taken so far.
+            (None, Some((leaf_address, _))) => {
+                leaf_address != address
+                    && (0..self.siblings.len())
+                        .all(|depth| bit(leaf_address, depth) == bit(address, depth))
+            }
+            (None, None) => true,
+            (Some(_), None) => false,
+        };
+        if !valid_leaf {
+            return false;
+        }
+
+        let leaf = self
+            .leaf
+            .map_or(EMPTY, |(address, hash)| account_leaf(&address, &hash));
+        let computed =
+            self.siblings
+                .iter()
+                .enumerate()
+                .rev()
+                .fold(leaf, |hash, (depth, sibling)| {
+                    if bit(address, depth) {
+                        hash_node(sibling, &hash)
+                    } else {
+                        hash_node(&hash, sibling)
+                    }
+                });
+
+        computed == *root
+    }
+}
+
+/// Root of the [`StateTree`] over `accounts`, built from scratch. A later
+/// record of an address replaces an earlier one.
+pub fn state_root<'a>(accounts: impl IntoIterator<Item = &'a Account>) -> BlockHash {
+    let mut tree = StateTree::new(|_: &NodeKey| Ok::<_, Infallible>(None));
+    let Ok(root) = accounts
+        .into_iter()
+        .try_for_each(|account| tree.insert(account))
+        .and_then(|()| tree.root());
+
+    root
+}
+
+/// Whether `address` turns right at `depth`.
+fn bit(address: &Address, depth: usize) -> bool {
+    address.as_ref()[depth / 8] & (0x80 >> (depth % 8)) != 0
+}
+
+fn hash_account(account: &Account) -> BlockHash {
+    let encoded =
+        bincode::encode_to_vec(account, config::standard()).expect("Account encoding cannot fail");
+    Sha3_256::digest(encoded).into()
+}
+
+fn account_leaf(address: &Address, hash: &BlockHash) -> BlockHash {
+    hash_leaf(&[address.as_ref(), hash.as_ref()])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Nodes = HashMap<NodeKey, StateNode>;
+
+    fn account(address: [u8; 32], balance: u64) -> Account {
+        let mut account = Account::new(Address::from(address));
+        account.balance = balance;
+        account
+    }
+
+    /// Sets or removes the record of `account` in the tree kept in `nodes`.
+    fn write(nodes: &mut Nodes, account: &Account, present: bool) {
+        let mut tree = StateTree::new(|key: &NodeKey| Ok::<_, Infallible>(nodes.get(key).copied()));
+        let Ok(()) = match present {
+            true => tree.insert(account),
+            false => tree.remove(&account.address),
+        };
+
+        for (key, node) in tree.into_changes() {
+            match node {
+                Some(node) => nodes.insert(key, node),
+                None => nodes.remove(&key),
+            };
+        }
+    }
+
+    /// Checks the tree in `nodes` against one built from `live` alone, and
+    /// the proofs it gives for `live` and `gone`.
+    fn check(nodes: &Nodes, live: &[Account], gone: &[Account]) {
+        let mut rebuilt = Nodes::new();
+        for account in live.iter().rev() {
+            write(&mut rebuilt, account, true);
+        }
+        assert_eq!(*nodes, rebuilt);
+
+        let tree = StateTree::new(|key: &NodeKey| Ok::<_, Infallible>(nodes.get(key).copied()));
+        let Ok(root) = tree.root();
+        assert_eq!(root, state_root(live));
+
+        for account in live {
+            let Ok(proof) = tree.proof(&account.address);
+            assert!(proof.verify(&account.address, Some(account), &root));
+            assert!(!proof.verify(&account.address, None, &root));
+
+            let mut forged = *account;
+            forged.balance += 1;
+            assert!(!proof.verify(&account.address, Some(&forged), &root));
+        }
+        for account in gone {
+            let Ok(proof) = tree.proof(&account.address);
+            assert!(proof.verify(&account.address, None, &root));
+            assert!(!proof.verify(&account.address, Some(account), &root));
+        }
+    }
+
+    #[test]
+    fn updates_only_touch_paths_and_match_a_rebuilt_tree() {
+        let mut last_bit = [0u8; 32];
+        last_bit[31] = 1;
+        let mut first_bit = [0u8; 32];
+        first_bit[0] = 0x80;
+        let mut second_bit = [0u8; 32];
+        second_bit[0] = 0x40;
+        let accounts = [
+            account([0u8; 32], 1),
+            // Shares every turn with the first one but the last.
+            account(last_bit, 2),
+            account(first_bit, 3),
+            account(second_bit, 4),
+            account([0xffu8; 32], 5),
+        ];
+
+        let mut nodes = Nodes::new();
+        check(&nodes, &[], &accounts);
+        for (i, account) in accounts.iter().enumerate() {
+            write(&mut nodes, account, true);
+            check(&nodes, &accounts[..=i], &accounts[i + 1..]);
+        }
+
+        let mut updated = accounts;
+        updated[1].balance = 20;
+        write(&mut nodes, &updated[1], true);
+        check(&nodes, &updated, &[]);
+
+        let mut live = updated.to_vec();
+        let mut gone = Vec::new();
+        for i in [0, 3, 4, 1, 2] {
+            write(&mut nodes, &updated[i], false);
+            live.retain(|a| *a != updated[i]);
+            gone.push(updated[i]);
+            check(&nodes, &live, &gone);
+        }
+        assert!(nodes.is_empty());
+    }
+}
@@ -1,7 +1,18 @@
+use std::time::{Duration, Instant};
+
+use lunaria::account::{Account, AccountProof, Address};
+use lunaria::block::{BlockHash, BlockHeader};
 use lunaria::client::{Client, DEFAULT_CREDS_LOCATION};
+use lunaria::transaction::{self, ChainId, DEFAULT_CHAIN_ID, Transaction};
+use pqcrypto::sign::falconpadded512;
+use pqcrypto::traits::sign::SecretKey;
+use tonic::Code;
 
-use validator::BalanceRequest;
 use validator::validator_client::ValidatorClient;
+use validator::{
+    AccountProofRequest, AccountRequest, BalanceRequest, NonceRequest, SubmitTransactionRequest,
+    TransactionRequest,
+};
 
 pub mod validator {
     tonic::include_proto!("validator");
@@ -24,6 +35,33 @@ enum Commands {
     Account,
     #[command(about = "Query balance of current account", long_about = None)]
     Balance,
+    #[command(about = "Look up a transaction by ID", long_about = None)]
+    Tx { id: String },
+    #[command(about = "Send LUN from the current account", long_about = None)]
+    Send {
+        to: String,
+        amount: u64,
+        #[arg(long, default_value_t = 1)]
+        fee: u64,
+        #[arg(long, default_value_t = DEFAULT_CHAIN_ID)]
+        chain_id: ChainId,
+        /// Wait until the transaction is included in a block.
+        #[arg(long)]
+        wait: bool,
+        /// Seconds to wait for inclusion before giving up.
+        #[arg(long, default_value_t = 600, requires = "wait")]
+        timeout: u64,
+    },
+    #[command(about = "Fetch an account's balance with a proof against a block", long_about = None)]
+    Proof {
+        /// Account to prove, defaults to the current account.
+        address: Option<String>,
+        /// Hash of a trusted block to prove against. Without it the proof is
+        /// checked against the tip the validator reports, which is not
+        /// verified.
+        #[arg(long)]
+        block: Option<String>,
+    },
 }
 
 async fn generate() -> Result<(), Box<dyn std::error::Error>> {
@@ -69,14 +107,240 @@ async fn get_balance() -> Result<(), Box<dyn std::error::Error>> {
     }
 }
 
+async fn get_transaction(id: String) -> Result<(), Box<dyn std::error::Error>> {
+    let mut grpc_client = ValidatorClient::connect("http://[::1]:50051").await?;
+
+    let request = tonic::Request::new(TransactionRequest { tx_id: id });
+    let response = grpc_client.get_transaction(request).await?;
+    let tx = response.get_ref();
+
+    println!("Transaction: {}", tx.tx_id);
+    println!("Block: #{} ({})", tx.block_index, tx.block_hash);
+    println!("Position: {}", tx.position);
+    println!("Confirmations: {}", tx.confirmations);
+    println!("From: {}", tx.from_address);
+    println!("To: {}", tx.to_address);
+    println!("Amount: {} LUN", tx.amount);
+    println!("Fee: {} LUN", tx.fee);
+    println!("Nonce: {}", tx.nonce);
+
+    Ok(())
+}
+
+async fn send(
+    to: String,
+    amount: u64,
+    fee: u64,
+    chain_id: ChainId,
+    wait: bool,
+    timeout: u64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let client = match Client::from_default_path() {
+        Ok(client) => client,
+        Err(_) => {
+            println!("Unable to open wallet at {DEFAULT_CREDS_LOCATION}");
+            return Ok(());
+        }
+    };
+    let to = Address::try_from(to.as_str())?;
+    let (pk, sk) = client.keypair();
+    let sk = falconpadded512::SecretKey::from_bytes(&sk)?;
+
+    let mut grpc_client = ValidatorClient::connect("http://[::1]:50051").await?;
+    let address = client.address().to_string();
+
+    let request = tonic::Request::new(NonceRequest {
+        address: address.clone(),
+    });
+    let nonce = grpc_client
+        .get_nonce(request)
+        .await?
+        .get_ref()
+        .pending_nonce;
+
+    // The public key only needs to travel with the transaction until the
+    // account has revealed it on chain.
+    let request = tonic::Request::new(AccountRequest {
+        address: address.clone(),
+    });
+    let revealed = !grpc_client
+        .get_account(request)
+        .await?
+        .get_ref()
+        .public_key
+        .is_empty();
+
+    let mut unsigned = Transaction::transfer(pk, to, amount, fee, nonce);
+    if revealed {
+        unsigned = unsigned.without_public_key();
+    }
+    let signed = transaction::sign(unsigned, chain_id, &sk);
+
+    let encoded = bincode::encode_to_vec(signed, bincode::config::standard())?;
+    let request = tonic::Request::new(SubmitTransactionRequest {
+        transaction: encoded,
+    });
+    let tx_id = grpc_client
+        .submit_transaction(request)
+        .await?
+        .get_ref()
+        .tx_id
+        .clone();
+    println!("Transaction: {tx_id}");
+
+    if !wait {
+        return Ok(());
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(timeout);
+    loop {
+        // Nonces are read first so that a transaction missing from the chain
+        // afterwards was already gone from the mempool too.
+        let request = tonic::Request::new(NonceRequest {
+            address: address.clone(),
+        });
+        let nonces = grpc_client.get_nonce(request).await?.into_inner();
+
+        let request = tonic::Request::new(TransactionRequest {
+            tx_id: tx_id.clone(),
+        });
+        match grpc_client.get_transaction(request).await {
+            Ok(response) => {
+                println!("Included in block #{}", response.get_ref().block_index);
+                return Ok(());
+            }
+            Err(status) if status.code() == Code::NotFound => {}
+            Err(status) => return Err(status.into()),
+        }
+
+        if nonces.nonce > nonce {
+            return Err(format!("another transaction with nonce {nonce} was included").into());
+        }
+        if nonces.pending_nonce <= nonce {
+            return Err("transaction was dropped from the mempool".into());
+        }
+        if Instant::now() >= deadline {
+            return Err(format!("transaction still pending after {timeout}s").into());
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+fn hash_bytes(bytes: &[u8]) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+    <[u8; 32]>::try_from(bytes)
+        .map_err(|_| format!("expected 32 bytes, got {}", bytes.len()).into())
+}
+
+async fn prove(
+    address: Option<String>,
+    block: Option<String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let address = match address {
+        Some(address) => Address::try_from(address.as_str())?,
+        None => match Client::from_default_path() {
+            Ok(client) => client.address(),
+            Err(_) => {
+                println!("Unable to open wallet at {DEFAULT_CREDS_LOCATION}");
+                return Ok(());
+            }
+        },
+    };
+
+    let trusted = block.as_deref().map(BlockHash::try_from).transpose()?;
+
+    let mut grpc_client = ValidatorClient::connect("http://[::1]:50051").await?;
+    let request = tonic::Request::new(AccountProofRequest {
+        address: address.to_string(),
+        block_hash: trusted.map(|hash| hash.to_string()).unwrap_or_default(),
+    });
+    let reply = grpc_client.get_account_proof(request).await?.into_inner();
+    let header = reply.header.ok_or("reply is missing the block header")?;
+
+    // Rebuild the header locally so that its hash, not the validator's word,
+    // ties the state root to the block.
+    let hash = BlockHash::from(hash_bytes(&header.hash)?);
+    let header = BlockHeader {
+        index: header.index,
+        timestamp: header.timestamp.into(),
+        previous_hash: hash_bytes(&header.previous_hash)?.into(),
+        transactions_root: hash_bytes(&header.transactions_root)?.into(),
+        state_root: hash_bytes(&header.state_root)?.into(),
+        difficulty: header.difficulty,
+        nonce: header.nonce,
+    };
+    if header.hash() != hash {
+        return Err(format!("header does not hash to block {hash}").into());
+    }
+    if hash.difficulty() < header.difficulty as usize {
+        return Err(format!("block {hash} does not meet its difficulty").into());
+    }
+    if let Some(trusted) = trusted
+        && trusted != hash
+    {
+        return Err(format!("proof is for block {hash}, not {trusted}").into());
+    }
+
+    let account: Option<Account> = if reply.account.is_empty() {
+        None
+    } else {
+        let (account, _) = bincode::decode_from_slice(&reply.account, bincode::config::standard())?;
+        Some(account)
+    };
+    let leaf = if reply.leaf_address.is_empty() {
+        None
+    } else {
+        Some((
+            Address::from(hash_bytes(&reply.leaf_address)?),
+            BlockHash::from(hash_bytes(&reply.leaf_hash)?),
+        ))
+    };
+    let siblings = reply
+        .siblings
+        .iter()
+        .map(|sibling| hash_bytes(sibling).map(BlockHash::from))
+        .collect::<Result<_, _>>()?;
+    let proof = AccountProof { leaf, siblings };
+
+    if !proof.verify(&address, account.as_ref(), &header.state_root) {
+        return Err(format!("proof does not match the state root of block {hash}").into());
+    }
+    let balance = account.map_or(0, |account| account.balance());
+    if balance != reply.balance {
+        return Err(format!(
+            "validator reported {} LUN, proof shows {balance} LUN",
+            reply.balance
+        )
+        .into());
+    }
+
+    println!("Address: {address}");
+    println!("{balance} LUN");
+    println!("Verified against block #{} ({hash})", header.index);
+    if trusted.is_none() {
+        println!("Unverified tip: pass --block <hash> to prove against a trusted block");
+    }
+
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let cli = Cli::parse();
 
     match cli.command {
-        Some(Commands::Generate {}) => generate().await,
-        Some(Commands::Account {}) => account().await,
-        Some(Commands::Balance {}) => get_balance().await,
+        Some(Commands::Generate) => generate().await,
+        Some(Commands::Account) => account().await,
+        Some(Commands::Balance) => get_balance().await,
+        Some(Commands::Tx { id }) => get_transaction(id).await,
+        Some(Commands::Send {
+            to,
+            amount,
+            fee,
+            chain_id,
+            wait,
+            timeout,
+        }) => send(to, amount, fee, chain_id, wait, timeout).await,
+        Some(Commands::Proof { address, block }) => prove(address, block).await,
         None => {
             Cli::command().print_help()?;
             Ok(())
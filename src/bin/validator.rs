@@ -1,18 +1,224 @@
-// use std::fs;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard};
+
+use clap::Parser;
+use tokio::sync::{Notify, broadcast};
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::{Stream, StreamExt};
 use tonic::{Request, Response, Status, transport::Server};
 
-use lunaria::{account::Address, ledger::Ledger};
+use lunaria::{
+    account::Address,
+    block::{Block, BlockError, BlockHash, CancellationToken, Miner},
+    client::Client,
+    genesis::GenesisConfig,
+    ledger::{ConsensusParams, Ledger, LedgerError, Snapshot},
+    mempool::{Mempool, MempoolError},
+    storage::{BlockLog, FileStorage, Storage},
+    transaction::{Transaction, TransactionError, TransactionId},
+};
 
 use validator::validator_server::{Validator, ValidatorServer};
-use validator::{BalanceReply, BalanceRequest};
+use validator::{
+    AccountProofReply, AccountProofRequest, AccountReply, AccountRequest, BalanceReply,
+    BalanceRequest, BlockHeader, MerkleStep, NonceReply, NonceRequest, SubmitTransactionReply,
+    SubmitTransactionRequest, SubscribeBlocksRequest, TransactionProofReply,
+    TransactionProofRequest, TransactionReply, TransactionRequest,
+};
 
 pub mod validator {
     tonic::include_proto!("validator");
 }
 
+#[derive(Parser)]
+#[command(author, version, about)]
+struct Cli {
+    /// Genesis configuration file, whose `[consensus]` table holds the
+    /// consensus parameters; the built-in genesis is used when omitted.
+    #[arg(long)]
+    genesis: Option<PathBuf>,
+    /// Directory the chain and its state are stored in.
+    #[arg(long, default_value = "./lunaria_data")]
+    data_dir: PathBuf,
+    /// Address block rewards are paid to; defaults to the local wallet. Blocks
+    /// are only produced when one is available.
+    #[arg(long)]
+    producer: Option<String>,
+    /// Snapshot to start from instead of genesis when the data directory is
+    /// empty.
+    #[arg(long, requires = "snapshot_hash")]
+    snapshot: Option<PathBuf>,
+    /// Trusted hash of the block the snapshot belongs to.
+    #[arg(long)]
+    snapshot_hash: Option<String>,
+    /// Block log, such as a copy of another node's blocks.log, whose blocks
+    /// are appended on startup.
+    #[arg(long)]
+    import: Option<PathBuf>,
+    /// Writes a snapshot of the state to this file and exits.
+    #[arg(long)]
+    export_snapshot: Option<PathBuf>,
+    /// Height to export the snapshot at; defaults to the tip.
+    #[arg(long, requires = "export_snapshot")]
+    export_height: Option<u64>,
+}
+
+/// Most transactions taken from the mempool for a single block.
+const MAX_BLOCK_TRANSACTIONS: usize = 1_000;
+
+/// Ledger of a node, kept in its data directory.
+type NodeLedger = Ledger<FileStorage>;
+
+/// Capacity of the channel new blocks are broadcast on. Subscribers lagging
+/// further behind miss blocks.
+const BLOCK_BROADCAST_CAPACITY: usize = 64;
+
+fn header_reply(block: &Block) -> BlockHeader {
+    let header = block.header();
+
+    BlockHeader {
+        index: header.index,
+        // Millisecond timestamps fit in a u64 for the next few hundred million years.
+        timestamp: u64::try_from(header.timestamp).unwrap_or(u64::MAX),
+        previous_hash: header.previous_hash.as_ref().to_vec(),
+        transactions_root: header.transactions_root.as_ref().to_vec(),
+        state_root: header.state_root.as_ref().to_vec(),
+        difficulty: header.difficulty,
+        nonce: header.nonce,
+        hash: block.hash().as_ref().to_vec(),
+    }
+}
+
+/// gRPC status for a transaction the mempool refused.
+fn rejection_status(e: MempoolError) -> Status {
+    let message = e.to_string();
+    match e {
+        MempoolError::DuplicateTransaction(_) => Status::already_exists(message),
+        MempoolError::ReplacementUnderpriced { .. } => Status::failed_precondition(message),
+        MempoolError::SenderLimit(_) | MempoolError::MempoolFull => {
+            Status::resource_exhausted(message)
+        }
+        MempoolError::InvalidTransaction(e) => match e {
+            LedgerError::TransactionError(e) => match e {
+                TransactionError::VerificationError { .. } => Status::unauthenticated(message),
+                TransactionError::AddressKeyMismatch { .. } => Status::permission_denied(message),
+                TransactionError::MissingPublicKey { .. }
+                | TransactionError::InsufficientBalance { .. } => {
+                    Status::failed_precondition(message)
+                }
+                TransactionError::InvalidNonce { .. } => Status::aborted(message),
+                TransactionError::Overflow(_) => Status::out_of_range(message),
+                TransactionError::SignatureBadLength(_) | TransactionError::FeeTooLow { .. } => {
+                    Status::invalid_argument(message)
+                }
+            },
+            LedgerError::ForbiddenMintTransaction(_) => Status::invalid_argument(message),
+            _ => Status::internal(message),
+        },
+    }
+}
+
+/// Background task extending the chain with blocks paying `address`.
+///
+/// Mining runs under a read lock on the ledger, so RPC handlers keep serving
+/// while a nonce is searched for. The write lock is only taken to append the
+/// block, which the ledger persists, and bring the mempool in line with the
+/// new tip.
+struct Producer {
+    ledger: Arc<RwLock<NodeLedger>>,
+    mempool: Arc<Mutex<Mempool>>,
+    blocks: broadcast::Sender<Block>,
+    address: Address,
+    cancel: CancellationToken,
+}
+
+impl Producer {
+    fn run(self) -> Result<(), LedgerError> {
+        while !self.cancel.is_cancelled() {
+            let miner = Miner::new()
+                .with_timestamp_refresh(true)
+                .with_cancellation_token(self.cancel.clone());
+
+            let built = {
+                let ledger = self.ledger.read().expect("ledger lock poisoned");
+                let candidates = self
+                    .mempool
+                    .lock()
+                    .expect("mempool lock poisoned")
+                    .select(MAX_BLOCK_TRANSACTIONS);
+                ledger.build_block_with(&miner, self.address, candidates)
+            };
+            let (block, report) = match built {
+                Ok(built) => built,
+                Err(LedgerError::BlockError(BlockError::MiningCancelled)) => break,
+                Err(e) => return Err(e),
+            };
+
+            for rejection in &report.rejected {
+                eprintln!(
+                    "rejected transaction {}: {}",
+                    rejection.transaction.id(),
+                    rejection.reason
+                );
+            }
+
+            {
+                let mut ledger = self.ledger.write().expect("ledger lock poisoned");
+                ledger.append_block(block.clone())?;
+                self.mempool
+                    .lock()
+                    .expect("mempool lock poisoned")
+                    .sync(&mut ledger)?;
+            }
+
+            let stats = miner.stats();
+            println!(
+                "Produced block #{} {} with {} transactions after {} hashes in {:.1}s ({:.0} H/s)",
+                block.index(),
+                block.hash(),
+                report.accepted.len(),
+                stats.hashes(),
+                stats.elapsed().as_secs_f64(),
+                stats.hash_rate()
+            );
+            // Sending only fails when nobody is subscribed.
+            let _ = self.blocks.send(block);
+        }
+
+        Ok(())
+    }
+}
+
+/// Node state shared between the RPC handlers and the producer.
+///
+/// Locks are always taken in the order ledger, then mempool.
 #[derive(Debug)]
 pub struct MyValidator {
-    ledger: Ledger,
+    ledger: Arc<RwLock<NodeLedger>>,
+    mempool: Arc<Mutex<Mempool>>,
+    blocks: broadcast::Sender<Block>,
+}
+
+/// A thread panicked while holding the named lock.
+#[derive(Debug)]
+struct LockPoisoned(&'static str);
+
+impl From<LockPoisoned> for Status {
+    fn from(e: LockPoisoned) -> Self {
+        Status::internal(format!("{} lock poisoned", e.0))
+    }
+}
+
+impl MyValidator {
+    fn ledger(&self) -> Result<RwLockReadGuard<'_, NodeLedger>, LockPoisoned> {
+        self.ledger.read().map_err(|_| LockPoisoned("ledger"))
+    }
+
+    fn mempool(&self) -> Result<MutexGuard<'_, Mempool>, LockPoisoned> {
+        self.mempool.lock().map_err(|_| LockPoisoned("mempool"))
+    }
 }
 
 #[tonic::async_trait]
@@ -25,10 +231,13 @@ impl Validator for MyValidator {
 
         let request_message = request.get_ref().clone();
         let balance = match Address::try_from(request_message.address.as_str()) {
-            Ok(address) => self.ledger.balance(address),
+            Ok(address) => self
+                .ledger()?
+                .balance(address)
+                .map_err(|e| Status::internal(e.to_string()))?,
             Err(e) => {
                 eprintln!("invalid address");
-                return Err(Status::internal(format!("invalid address: {e:?}")));
+                return Err(Status::invalid_argument(format!("invalid address: {e:?}")));
             }
         };
 
@@ -39,19 +248,392 @@ impl Validator for MyValidator {
 
         Ok(Response::new(reply))
     }
+
+    async fn get_nonce(
+        &self,
+        request: Request<NonceRequest>,
+    ) -> Result<Response<NonceReply>, Status> {
+        println!("Got a request: {:?}", request);
+
+        let request_message = request.get_ref().clone();
+        let address = match Address::try_from(request_message.address.as_str()) {
+            Ok(address) => address,
+            Err(e) => {
+                eprintln!("invalid address");
+                return Err(Status::invalid_argument(format!("invalid address: {e:?}")));
+            }
+        };
+        let ledger = self.ledger()?;
+        let nonce = ledger
+            .nonce(address)
+            .map_err(|e| Status::internal(e.to_string()))?;
+        let pending_nonce = self
+            .mempool()?
+            .pending_nonce(&ledger, address)
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        let reply = NonceReply {
+            address: request_message.address,
+            nonce,
+            pending_nonce,
+        };
+
+        Ok(Response::new(reply))
+    }
+
+    async fn get_account(
+        &self,
+        request: Request<AccountRequest>,
+    ) -> Result<Response<AccountReply>, Status> {
+        println!("Got a request: {:?}", request);
+
+        let request_message = request.get_ref().clone();
+        let account = match Address::try_from(request_message.address.as_str()) {
+            Ok(address) => self
+                .ledger()?
+                .account(address)
+                .map_err(|e| Status::internal(e.to_string()))?,
+            Err(e) => {
+                eprintln!("invalid address");
+                return Err(Status::invalid_argument(format!("invalid address: {e:?}")));
+            }
+        };
+
+        let reply = AccountReply {
+            address: request_message.address,
+            balance: account.balance(),
+            nonce: account.nonce(),
+            public_key: account
+                .public_key()
+                .map(|pk| pk.to_vec())
+                .unwrap_or_default(),
+        };
+
+        Ok(Response::new(reply))
+    }
+
+    async fn get_transaction(
+        &self,
+        request: Request<TransactionRequest>,
+    ) -> Result<Response<TransactionReply>, Status> {
+        println!("Got a request: {:?}", request);
+
+        let request_message = request.get_ref().clone();
+        let id = TransactionId::try_from(request_message.tx_id.as_str())
+            .map_err(|e| Status::invalid_argument(format!("invalid transaction id: {e}")))?;
+
+        let ledger = self.ledger()?;
+        let (block, position) = ledger
+            .find_transaction(&id)
+            .map_err(|e| Status::internal(e.to_string()))?
+            .ok_or_else(|| Status::not_found(format!("transaction {id} not found")))?;
+        let transaction = &block.transactions()[position];
+        let tip = ledger.last().map_err(|e| Status::internal(e.to_string()))?;
+
+        let reply = TransactionReply {
+            tx_id: request_message.tx_id,
+            block_index: block.index(),
+            block_hash: block.hash().to_string(),
+            position: position as u64,
+            confirmations: tip.index() - block.index() + 1,
+            from_address: transaction.from_address.to_string(),
+            to_address: transaction.to_address.to_string(),
+            amount: transaction.amount,
+            nonce: transaction.nonce,
+            fee: transaction.fee,
+        };
+
+        Ok(Response::new(reply))
+    }
+
+    async fn get_transaction_proof(
+        &self,
+        request: Request<TransactionProofRequest>,
+    ) -> Result<Response<TransactionProofReply>, Status> {
+        println!("Got a request: {:?}", request);
+
+        let request_message = request.get_ref().clone();
+        let id = TransactionId::try_from(request_message.tx_id.as_str())
+            .map_err(|e| Status::invalid_argument(format!("invalid transaction id: {e}")))?;
+
+        let ledger = self.ledger()?;
+        let (block, position) = ledger
+            .find_transaction(&id)
+            .map_err(|e| Status::internal(e.to_string()))?
+            .ok_or_else(|| Status::not_found(format!("transaction {id} not found")))?;
+        let proof = block
+            .transaction_proof(position)
+            .ok_or_else(|| Status::internal(format!("no proof for transaction {id}")))?;
+
+        let reply = TransactionProofReply {
+            tx_id: request_message.tx_id,
+            header: Some(header_reply(&block)),
+            position: position as u64,
+            proof: proof
+                .steps
+                .iter()
+                .map(|step| MerkleStep {
+                    sibling: step.sibling.as_ref().to_vec(),
+                    left: step.left,
+                })
+                .collect(),
+        };
+
+        Ok(Response::new(reply))
+    }
+
+    async fn get_account_proof(
+        &self,
+        request: Request<AccountProofRequest>,
+    ) -> Result<Response<AccountProofReply>, Status> {
+        println!("Got a request: {:?}", request);
+
+        let request_message = request.get_ref().clone();
+        let address = Address::try_from(request_message.address.as_str())
+            .map_err(|e| Status::invalid_argument(format!("invalid address: {e:?}")))?;
+
+        let ledger = self.ledger()?;
+        let block = if request_message.block_hash.is_empty() {
+            ledger.last().map_err(|e| Status::internal(e.to_string()))?
+        } else {
+            let hash = BlockHash::try_from(request_message.block_hash.as_str())
+                .map_err(|e| Status::invalid_argument(format!("invalid block hash: {e}")))?;
+            ledger
+                .block(&hash)
+                .map_err(|e| Status::internal(e.to_string()))?
+                .ok_or_else(|| Status::not_found(format!("block {hash} not found")))?
+        };
+
+        let active = ledger
+            .block_at(block.index())
+            .map_err(|e| Status::internal(e.to_string()))?;
+        if active.as_ref().map(Block::hash) != Some(block.hash()) {
+            return Err(Status::failed_precondition(format!(
+                "block {} is not on the active chain",
+                block.hash()
+            )));
+        }
+
+        let (account, proof) = ledger
+            .account_proof(address, block.index())
+            .map_err(|e| Status::internal(e.to_string()))?;
+        let encoded = account
+            .map(|account| bincode::encode_to_vec(account, bincode::config::standard()))
+            .transpose()
+            .map_err(|e| Status::internal(e.to_string()))?
+            .unwrap_or_default();
+        let (leaf_address, leaf_hash) = proof
+            .leaf
+            .map(|(address, hash)| (address.as_ref().to_vec(), hash.as_ref().to_vec()))
+            .unwrap_or_default();
+
+        let reply = AccountProofReply {
+            address: request_message.address,
+            header: Some(header_reply(&block)),
+            account: encoded,
+            balance: account.map_or(0, |account| account.balance()),
+            leaf_address,
+            leaf_hash,
+            siblings: proof
+                .siblings
+                .iter()
+                .map(|sibling| sibling.as_ref().to_vec())
+                .collect(),
+        };
+
+        Ok(Response::new(reply))
+    }
+
+    async fn submit_transaction(
+        &self,
+        request: Request<SubmitTransactionRequest>,
+    ) -> Result<Response<SubmitTransactionReply>, Status> {
+        println!("Got a request: {:?}", request);
+
+        let (transaction, _): (Transaction, usize) =
+            bincode::decode_from_slice(&request.get_ref().transaction, bincode::config::standard())
+                .map_err(|e| Status::invalid_argument(format!("invalid transaction: {e}")))?;
+
+        let ledger = self.ledger()?;
+        let id = self
+            .mempool()?
+            .insert(&ledger, transaction)
+            .map_err(rejection_status)?;
+
+        let reply = SubmitTransactionReply {
+            tx_id: id.to_string(),
+        };
+
+        Ok(Response::new(reply))
+    }
+
+    type SubscribeBlocksStream =
+        Pin<Box<dyn Stream<Item = Result<BlockHeader, Status>> + Send + 'static>>;
+
+    async fn subscribe_blocks(
+        &self,
+        request: Request<SubscribeBlocksRequest>,
+    ) -> Result<Response<Self::SubscribeBlocksStream>, Status> {
+        println!("Got a request: {:?}", request);
+
+        // Blocks missed by a lagging subscriber are skipped.
+        let stream = BroadcastStream::new(self.blocks.subscribe())
+            .filter_map(|block| block.ok().map(|block| header_reply(&block)))
+            .map(Ok);
+
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
+
+/// Appends the blocks of the log at `path` that extend a known block, and
+/// returns how many were new.
+fn import_blocks(
+    ledger: &mut NodeLedger,
+    path: &Path,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    if !path.exists() {
+        return Err(format!("no block log at {}", path.display()).into());
+    }
+
+    let mut imported = 0;
+    for block in BlockLog::read(path)? {
+        match ledger.append_block(block?) {
+            Ok(()) => imported += 1,
+            // Blocks already known or below a snapshot are skipped.
+            Err(LedgerError::DuplicateBlock(_) | LedgerError::UnknownParent(_)) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(imported)
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let addr = "[::1]:50051".parse()?;
 
-    let ledger = Ledger::new()?;
-    let validator = MyValidator { ledger };
+    let cli = Cli::parse();
+    let (genesis, params) = match cli.genesis {
+        Some(path) => (
+            GenesisConfig::from_file(&path)?,
+            ConsensusParams::from_file(&path)?,
+        ),
+        None => (GenesisConfig::default(), ConsensusParams::default()),
+    };
+
+    let storage = FileStorage::open(&cli.data_dir)?;
+    if storage.truncated() > 0 {
+        println!(
+            "Dropped {} bytes of unfinished writes from {}",
+            storage.truncated(),
+            storage.dir().display()
+        );
+    }
+
+    let mut ledger = match cli.snapshot {
+        Some(path) if storage.committed_tip().is_none() => {
+            let snapshot = Snapshot::from_bytes(&fs::read(path)?)?;
+            let hash = snapshot.block().map(|block| block.hash().to_string());
+            if hash != cli.snapshot_hash {
+                return Err(format!(
+                    "snapshot block {} does not match the trusted hash",
+                    hash.unwrap_or_default()
+                )
+                .into());
+            }
+            Ledger::from_snapshot(storage, genesis, params, snapshot)?
+        }
+        Some(_) => {
+            println!("Data directory already holds a chain, ignoring the snapshot");
+            Ledger::open(storage, genesis, params)?
+        }
+        None => Ledger::open(storage, genesis, params)?,
+    };
+    println!("Genesis block: {}", ledger.genesis_config().genesis_hash);
+    if ledger.base() > 0 {
+        println!("Started from snapshot at block #{}", ledger.base());
+    }
+
+    if let Some(path) = cli.import {
+        let imported = import_blocks(&mut ledger, &path)?;
+        println!("Imported {imported} blocks from {}", path.display());
+    }
+    println!("Tip: #{} {}", ledger.height(), ledger.last()?.hash());
+
+    if let Some(path) = cli.export_snapshot {
+        let snapshot = ledger.snapshot(cli.export_height.unwrap_or(ledger.height()))?;
+        fs::write(&path, snapshot.encode()?)?;
+        if let Some(block) = snapshot.block() {
+            println!(
+                "Exported the state at block #{} {} to {}",
+                block.index(),
+                block.hash(),
+                path.display()
+            );
+        }
+        return Ok(());
+    }
+
+    let ledger = Arc::new(RwLock::new(ledger));
+    let mempool = Arc::new(Mutex::new(Mempool::new()));
+    let (blocks, _) = broadcast::channel(BLOCK_BROADCAST_CAPACITY);
+    let cancel = CancellationToken::new();
+    // The node stops serving once it stops producing, rather than carrying on
+    // with a chain that no longer grows.
+    let producer_stopped = Arc::new(Notify::new());
+
+    let producer_address = match cli.producer {
+        Some(address) => Some(Address::try_from(address.as_str())?),
+        None => Client::from_default_path()
+            .ok()
+            .map(|client| client.address()),
+    };
+    let producer = match producer_address {
+        Some(address) => {
+            println!("Producing blocks for {address}");
+            let producer = Producer {
+                ledger: ledger.clone(),
+                mempool: mempool.clone(),
+                blocks: blocks.clone(),
+                address,
+                cancel: cancel.clone(),
+            };
+            let stopped = producer_stopped.clone();
+            Some(tokio::task::spawn_blocking(move || {
+                let result = producer.run();
+                if let Err(e) = &result {
+                    eprintln!("Block production failed, shutting down: {e}");
+                }
+                stopped.notify_one();
+                result
+            }))
+        }
+        None => {
+            println!("No producer address, not producing blocks");
+            None
+        }
+    };
+
+    let validator = MyValidator {
+        ledger,
+        mempool,
+        blocks,
+    };
 
     Server::builder()
         .add_service(ValidatorServer::new(validator))
-        .serve(addr)
+        .serve_with_shutdown(addr, async {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = producer_stopped.notified() => {}
+            }
+        })
         .await?;
 
+    cancel.cancel();
+    if let Some(producer) = producer {
+        producer.await??;
+    }
+
     Ok(())
 }
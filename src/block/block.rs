@@ -1,143 +1,159 @@
 use super::error::BlockError;
-use super::hash::{BlockHash, BlockHasher};
-use crate::account::Address;
-use crate::transaction::{Transaction, TransactionType};
+use super::hash::BlockHash;
+use super::header::BlockHeader;
+use super::merkle::{MerkleProof, MerkleTree};
+use super::miner::Miner;
+use crate::transaction::{Transaction, TransactionId};
 
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::{fmt, u64};
+use std::fmt;
 
 use bincode::{Decode, Encode, config};
-use rayon::prelude::*;
-use sha3::{Digest, Sha3_256, digest::FixedOutput};
-
-pub const DIFFICULTY: usize = 8;
 
 #[derive(Debug, Clone, Encode, Decode, PartialEq)]
 pub struct Block {
-    index: u64,
-    timestamp: u128,
+    header: BlockHeader,
     hash: BlockHash,
-    previous_hash: BlockHash,
     transactions: Vec<Transaction>,
-    nonce: u64,
 }
 
 impl Block {
+    /// Mines a block whose hash has at least `difficulty` leading zero bits.
+    ///
+    /// `state_root` commits to the account records left by applying
+    /// `transactions`, which the caller computes.
     pub fn forge(
         index: u64,
         timestamp: u128,
         previous_hash: BlockHash,
         transactions: Vec<Transaction>,
+        state_root: BlockHash,
+        difficulty: u32,
     ) -> Result<Self, BlockError> {
-        Self::forge_with_difficulty(index, timestamp, previous_hash, transactions, DIFFICULTY)
+        Self::forge_with(
+            &Miner::default(),
+            index,
+            timestamp,
+            previous_hash,
+            transactions,
+            state_root,
+            difficulty,
+        )
     }
 
-    fn forge_with_difficulty(
+    /// Like [`Block::forge`], but mines with `miner` so the search can be
+    /// cancelled, observed or given its own nonce range.
+    pub fn forge_with(
+        miner: &Miner,
         index: u64,
         timestamp: u128,
         previous_hash: BlockHash,
         transactions: Vec<Transaction>,
-        difficulty: usize,
+        state_root: BlockHash,
+        difficulty: u32,
     ) -> Result<Self, BlockError> {
-        let encoded_transactions =
-            bincode::encode_to_vec(&transactions, bincode::config::standard())
-                .map_err(BlockError::TransactionEncodeError)?;
-        let base_hasher = BlockHasher::new(index, timestamp, previous_hash, encoded_transactions);
-        let found = AtomicBool::new(false);
-
-        let max_attempts = 1_000_000_000u64;
-
-        let result = (0..max_attempts).into_par_iter().find_map_first(|nonce| {
-            if found.load(Ordering::Acquire) {
-                return None;
-            }
-
-            let mut hasher = base_hasher.clone();
-            let hash = hasher.hash_nonce(nonce);
-
-            if hash.difficulty() >= difficulty {
-                found.store(true, Ordering::Relaxed);
-                Some((nonce, hash))
-            } else {
-                None
-            }
-        });
-
-        let (nonce, hash) = result.ok_or(BlockError::NonceTooHard)?;
-
-        Ok(Block {
+        let header = BlockHeader {
             index,
             timestamp,
             previous_hash,
-            transactions,
-            nonce,
+            transactions_root: Self::transactions_tree(&transactions).root(),
+            state_root,
+            difficulty,
+            nonce: 0,
+        };
+
+        let (header, hash) = miner.mine(header)?;
+
+        Ok(Block {
+            header,
             hash,
+            transactions,
         })
     }
 
+    fn transactions_tree(transactions: &[Transaction]) -> MerkleTree {
+        let ids: Vec<TransactionId> = transactions.iter().map(Transaction::id).collect();
+        MerkleTree::new(&ids)
+    }
+
+    /// Proof that the transaction at `position` is committed to by this
+    /// block's header.
+    pub fn transaction_proof(&self, position: usize) -> Option<MerkleProof> {
+        Self::transactions_tree(&self.transactions).proof(position)
+    }
+
     pub fn from_bytes(bytes: Vec<u8>) -> Result<Self, BlockError> {
         let config = config::standard();
         let (decoded, _): (Self, usize) = bincode::decode_from_slice(&bytes, config)?;
         Ok(decoded)
     }
 
-    pub fn genesis() -> Result<Self, BlockError> {
-        let genesis_transactions = vec![Transaction {
-            tx_type: TransactionType::Mint,
-            from_address: Address::from([0u8; 32]),
-            from_public_key: [0u8; 897],
-            signature: [0u8; 752],
-            to_address: Address::try_from("9JEuZSy4CmRM8wMiE368Bx5jkgK5SLH1KvRDiUcNRjsV")
-                .map_err(BlockError::GenesisTransactionError)?,
-            amount: u64::MAX / 2,
-        }];
-
-        Self::forge_with_difficulty(
-            0,
-            0,
-            BlockHash::from([0u8; 32]),
-            genesis_transactions,
-            DIFFICULTY,
-        )
-    }
-
+    /// Checks that the stored hash is the canonical header hash, that the
+    /// header's Merkle root commits to the block's transactions, and that the
+    /// proof of work meets the difficulty declared in the header.
     pub fn verify_hash(&self) -> Result<(), BlockError> {
-        let mut hasher = Sha3_256::new();
-
-        hasher.update(self.index.to_le_bytes());
-        hasher.update(self.timestamp.to_le_bytes());
-        hasher.update(&self.previous_hash);
-
-        let encoded_transactions =
-            bincode::encode_to_vec(&self.transactions, bincode::config::standard())
-                .map_err(BlockError::TransactionEncodeError)?;
-
-        hasher.update(&encoded_transactions);
-
-        let computed: BlockHash = hasher.finalize_fixed().into();
-        let current = self.hash();
+        let transactions_root = Self::transactions_tree(&self.transactions).root();
+        if self.header.transactions_root != transactions_root {
+            return Err(BlockError::InvalidTransactionsRoot {
+                got: self.header.transactions_root,
+                want: transactions_root,
+            });
+        }
 
-        if *current != computed {
-            Err(BlockError::InvalidHash {
-                got: self.hash().clone(),
+        let computed = self.header.hash();
+        if self.hash != computed {
+            return Err(BlockError::InvalidHash {
+                got: self.hash,
                 want: computed,
-            })
-        } else {
-            Ok(())
+            });
         }
+
+        if self.hash.difficulty() < self.header.difficulty as usize {
+            return Err(BlockError::InsufficientDifficulty {
+                got: self.hash.difficulty(),
+                want: self.header.difficulty as usize,
+            });
+        }
+
+        Ok(())
     }
 
     pub fn encode(&self) -> Result<Vec<u8>, BlockError> {
         let config = config::standard();
-        bincode::encode_to_vec(&self, config).map_err(BlockError::from)
+        bincode::encode_to_vec(self, config).map_err(BlockError::from)
     }
 
     pub fn hash(&self) -> &BlockHash {
         &self.hash
     }
 
+    /// Expected number of hashes needed to mine this block, from its declared
+    /// difficulty rather than the hash it happened to find.
+    pub fn work(&self) -> u128 {
+        1u128 << self.header.difficulty.min(127)
+    }
+
+    pub fn header(&self) -> &BlockHeader {
+        &self.header
+    }
+
     pub fn index(&self) -> u64 {
-        self.index
+        self.header.index
+    }
+
+    pub fn timestamp(&self) -> u128 {
+        self.header.timestamp
+    }
+
+    pub fn difficulty(&self) -> u32 {
+        self.header.difficulty
+    }
+
+    pub fn previous_hash(&self) -> &BlockHash {
+        &self.header.previous_hash
+    }
+
+    pub fn state_root(&self) -> &BlockHash {
+        &self.header.state_root
     }
 
     pub fn transactions(&self) -> &[Transaction] {
@@ -160,9 +176,136 @@ impl fmt::Display for Block {
              Timestamp      : {}\n\
              Hash           : {}\n\
              Previous Hash  : {}\n\
+             State Root     : {}\n\
              Transactions   : [\n    {}\n]\n\
+             Difficulty     : {}\n\
              Nonce          : {}",
-            self.index, self.timestamp, self.hash, self.previous_hash, transactions_str, self.nonce
+            self.header.index,
+            self.header.timestamp,
+            self.hash,
+            self.header.previous_hash,
+            self.header.state_root,
+            transactions_str,
+            self.header.difficulty,
+            self.header.nonce
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account::Address;
+
+    fn mined_block() -> Block {
+        let transactions = vec![Transaction::mint(Address::from([7u8; 32]), 50, 1)];
+        Block::forge(
+            1,
+            1_700_000_000_000,
+            BlockHash::from([1u8; 32]),
+            transactions,
+            BlockHash::from([2u8; 32]),
+            8,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn header_hash_matches_vector() {
+        let header = BlockHeader {
+            index: 7,
+            timestamp: 1_700_000_000_000,
+            previous_hash: BlockHash::from([1u8; 32]),
+            transactions_root: BlockHash::from([2u8; 32]),
+            state_root: BlockHash::from([3u8; 32]),
+            difficulty: 12,
+            nonce: 0xdead_beef,
+        };
+
+        assert_eq!(
+            header.hash().to_string(),
+            "e7e04a0ee094a145b99db226db677ae0b4b65631b662e1e0dc0cb75fed09d766"
+        );
+        assert_eq!(header.hasher().hash_nonce(header.nonce), header.hash());
+    }
+
+    #[test]
+    fn mined_block_verifies() {
+        let block = mined_block();
+
+        block.verify_hash().unwrap();
+        assert_eq!(block.header().hash(), *block.hash());
+        assert!(block.hash().difficulty() >= 8);
+    }
+
+    #[test]
+    fn tampered_block_fails_verification() {
+        let tampers: [fn(&mut Block); 9] = [
+            |b| b.header.index += 1,
+            |b| b.header.timestamp += 1,
+            |b| b.header.previous_hash = BlockHash::ZERO,
+            |b| b.header.transactions_root = BlockHash::ZERO,
+            |b| b.header.state_root = BlockHash::ZERO,
+            |b| b.header.difficulty -= 1,
+            |b| b.header.nonce += 1,
+            |b| b.hash = BlockHash::ZERO,
+            |b| {
+                b.transactions
+                    .push(Transaction::mint(Address::from([8u8; 32]), 1, 1))
+            },
+        ];
+
+        let block = mined_block();
+        for (i, tamper) in tampers.iter().enumerate() {
+            let mut forged = block.clone();
+            tamper(&mut forged);
+            assert!(forged.verify_hash().is_err(), "tamper {i} went unnoticed");
+        }
+    }
+
+    #[test]
+    fn transaction_proofs_verify_against_the_header() {
+        let transactions: Vec<Transaction> = (1..=5)
+            .map(|i| Transaction::mint(Address::from([i; 32]), 50, i as u64))
+            .collect();
+        let block = Block::forge(
+            1,
+            1_700_000_000_000,
+            BlockHash::from([1u8; 32]),
+            transactions.clone(),
+            BlockHash::from([2u8; 32]),
+            8,
+        )
+        .unwrap();
+        let root = block.header().transactions_root;
+
+        for (position, t) in transactions.iter().enumerate() {
+            let proof = block.transaction_proof(position).unwrap();
+            assert!(proof.verify(&t.id(), &root));
+            assert!(!proof.verify(&t.id(), &block.header().state_root));
+        }
+        assert_eq!(block.transaction_proof(transactions.len()), None);
+    }
+
+    #[test]
+    fn rehashed_block_needs_proof_of_work() {
+        // Consistent with its header, but without the work to back it.
+        let mut forged = mined_block();
+        forged.header.state_root = (0u8..)
+            .map(|byte| BlockHash::from([byte; 32]))
+            .find(|root| {
+                let header = BlockHeader {
+                    state_root: *root,
+                    ..forged.header
+                };
+                header.hash().difficulty() < 8
+            })
+            .unwrap();
+        forged.hash = forged.header.hash();
+
+        assert!(matches!(
+            forged.verify_hash(),
+            Err(BlockError::InsufficientDifficulty { .. })
+        ));
+    }
+}
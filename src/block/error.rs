@@ -1,17 +1,25 @@
 use thiserror::Error;
 
-use crate::account::AddressParseError;
-
 use super::hash::BlockHash;
 
 #[derive(Error, Debug)]
 pub enum BlockError {
     #[error("InvalidHash: got: {got}, want: {want}")]
     InvalidHash { got: BlockHash, want: BlockHash },
+    #[error("InvalidTransactionsRoot: got: {got}, want: {want}")]
+    InvalidTransactionsRoot { got: BlockHash, want: BlockHash },
+    #[error("InvalidStateRoot: got: {got}, want: {want}")]
+    InvalidStateRoot { got: BlockHash, want: BlockHash },
     #[error("InvalidPreviousHash: got: {got}, want: {want}")]
     InvalidPreviousHash { got: BlockHash, want: BlockHash },
     #[error("InvalidIndex: got: {got}, want: {want}")]
     InvalidIndex { got: u64, want: u64 },
+    #[error("InvalidTimestamp: got: {got}, previous: {previous}")]
+    InvalidTimestamp { got: u128, previous: u128 },
+    #[error("InvalidDifficulty: got: {got}, want: {want}")]
+    InvalidDifficulty { got: u32, want: u32 },
+    #[error("InsufficientDifficulty: got: {got}, want: {want}")]
+    InsufficientDifficulty { got: usize, want: usize },
 
     #[error("EncodeError: {0}")]
     EncodeError(#[from] bincode::error::EncodeError),
@@ -20,11 +28,20 @@ pub enum BlockError {
     #[error("TransactionEncodeError: {0}")]
     TransactionEncodeError(bincode::error::EncodeError),
 
-    #[error("GenesisTransactionError: {0}")]
-    GenesisTransactionError(#[from] AddressParseError),
-
     #[error("InvalidNonce: {0}")]
     InvalidNonce(u64),
     #[error("NonceTooHard")]
     NonceTooHard,
+    #[error("EmptyNonceRange")]
+    EmptyNonceRange,
+    #[error("MiningCancelled")]
+    MiningCancelled,
+}
+
+#[derive(Error, Debug)]
+pub enum BlockHashParseError {
+    #[error("Hex decoding error: {0}")]
+    Hex(hex::FromHexError),
+    #[error("InputLength: Invalid block hash length (expected 32 bytes)")]
+    InputLength,
 }
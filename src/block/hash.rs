@@ -4,10 +4,15 @@ use sha3::{Digest, Sha3_256};
 use std::fmt;
 use typenum::U32;
 
-#[derive(PartialEq, Clone, Copy, Debug, Encode, Decode)]
+use super::error::BlockHashParseError;
+use super::header::BlockHeader;
+
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug, Encode, Decode)]
 pub struct BlockHash([u8; 32]);
 
 impl BlockHash {
+    pub const ZERO: Self = Self([0u8; 32]);
+
     pub fn difficulty(&self) -> usize {
         let mut count = 0;
         for byte in self.0.iter() {
@@ -43,36 +48,47 @@ impl From<[u8; 32]> for BlockHash {
     }
 }
 
+impl TryFrom<&str> for BlockHash {
+    type Error = BlockHashParseError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let bytes = hex::decode(value).map_err(BlockHashParseError::Hex)?;
+        let array: [u8; 32] = bytes
+            .try_into()
+            .map_err(|_| BlockHashParseError::InputLength)?;
+        Ok(Self(array))
+    }
+}
+
 impl AsRef<[u8]> for BlockHash {
     fn as_ref(&self) -> &[u8] {
         &self.0
     }
 }
 
+/// Canonical block header hash: SHA3-256 over the big-endian header fields,
+/// in declaration order, with the nonce last.
 #[derive(Clone)]
 pub struct BlockHasher {
     state: Sha3_256,
 }
 
 impl BlockHasher {
-    pub fn new(
-        index: u64,
-        timestamp: u128,
-        previous_hash: BlockHash,
-        encoded_transactions: Vec<u8>,
-    ) -> Self {
+    pub fn new(header: &BlockHeader) -> Self {
         let mut hasher = Sha3_256::new();
-        hasher.update(index.to_be_bytes());
-        hasher.update(timestamp.to_be_bytes());
-        hasher.update(previous_hash.0);
-        hasher.update(encoded_transactions);
+        hasher.update(header.index.to_be_bytes());
+        hasher.update(header.timestamp.to_be_bytes());
+        hasher.update(header.previous_hash.0);
+        hasher.update(header.transactions_root.0);
+        hasher.update(header.state_root.0);
+        hasher.update(header.difficulty.to_be_bytes());
 
         BlockHasher { state: hasher }
     }
 
-    pub fn hash_nonce(&mut self, nonce: u64) -> BlockHash {
-        self.state.update(nonce.to_be_bytes());
-        let bytes = self.state.finalize_reset();
-        BlockHash(bytes.into())
+    pub fn hash_nonce(&self, nonce: u64) -> BlockHash {
+        let mut state = self.state.clone();
+        state.update(nonce.to_be_bytes());
+        BlockHash(state.finalize().into())
     }
 }
@@ -0,0 +1,30 @@
+use bincode::{Decode, Encode};
+
+use super::hash::{BlockHash, BlockHasher};
+
+/// Fields of a block that are committed to by its proof of work.
+#[derive(Debug, Clone, Copy, Encode, Decode, PartialEq)]
+pub struct BlockHeader {
+    pub index: u64,
+    pub timestamp: u128,
+    pub previous_hash: BlockHash,
+    /// Merkle root over the IDs of the block's transactions.
+    pub transactions_root: BlockHash,
+    /// Root of the [`StateTree`](crate::account::StateTree) over every account
+    /// record once the block is applied.
+    pub state_root: BlockHash,
+    /// Required leading zero bits of the block hash.
+    pub difficulty: u32,
+    pub nonce: u64,
+}
+
+impl BlockHeader {
+    /// Hasher primed with every header field except the nonce.
+    pub fn hasher(&self) -> BlockHasher {
+        BlockHasher::new(self)
+    }
+
+    pub fn hash(&self) -> BlockHash {
+        self.hasher().hash_nonce(self.nonce)
+    }
+}
@@ -0,0 +1,203 @@
+use bincode::{Decode, Encode};
+use sha3::{Digest, Sha3_256, digest::FixedOutput};
+
+use super::hash::BlockHash;
+use crate::transaction::TransactionId;
+
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+/// Binary Merkle tree over the transaction IDs of a block.
+///
+/// Leaves and inner nodes are hashed with distinct prefixes so that one can
+/// never be passed off as the other. A node without a sibling is promoted to
+/// the next level unchanged rather than paired with itself. The root of an
+/// empty tree is all zeros.
+#[derive(Debug, Clone)]
+pub struct MerkleTree {
+    levels: Vec<Vec<BlockHash>>,
+}
+
+/// Sibling hashes from a leaf up to the root.
+#[derive(Debug, Clone, PartialEq, Encode, Decode)]
+pub struct MerkleProof {
+    pub steps: Vec<MerkleStep>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Encode, Decode)]
+pub struct MerkleStep {
+    pub sibling: BlockHash,
+    /// Whether the sibling is the left operand of the parent hash.
+    pub left: bool,
+}
+
+impl MerkleTree {
+    pub fn new(ids: &[TransactionId]) -> Self {
+        let leaves: Vec<BlockHash> = ids.iter().map(|id| hash_leaf(&[id.as_ref()])).collect();
+        let mut levels = vec![leaves];
+
+        while levels.last().is_some_and(|level| level.len() > 1) {
+            let next = levels
+                .last()
+                .unwrap()
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => hash_node(left, right),
+                    [single] => *single,
+                    _ => unreachable!(),
+                })
+                .collect();
+            levels.push(next);
+        }
+
+        Self { levels }
+    }
+
+    pub fn root(&self) -> BlockHash {
+        self.levels
+            .last()
+            .and_then(|level| level.first())
+            .copied()
+            .unwrap_or(BlockHash::from([0u8; 32]))
+    }
+
+    /// Inclusion proof for the leaf at `position`.
+    pub fn proof(&self, position: usize) -> Option<MerkleProof> {
+        if position >= self.levels.first()?.len() {
+            return None;
+        }
+
+        let mut steps = Vec::new();
+        let mut index = position;
+        for level in &self.levels[..self.levels.len() - 1] {
+            let sibling = index ^ 1;
+            if let Some(hash) = level.get(sibling) {
+                steps.push(MerkleStep {
+                    sibling: *hash,
+                    left: sibling < index,
+                });
+            }
+            index /= 2;
+        }
+
+        Some(MerkleProof { steps })
+    }
+}
+
+impl MerkleProof {
+    /// Checks that `id` is a leaf of the tree whose root is `root`.
+    pub fn verify(&self, id: &TransactionId, root: &BlockHash) -> bool {
+        let leaf = hash_leaf(&[id.as_ref()]);
+        let computed = self.steps.iter().fold(leaf, |hash, step| {
+            if step.left {
+                hash_node(&step.sibling, &hash)
+            } else {
+                hash_node(&hash, &step.sibling)
+            }
+        });
+
+        computed == *root
+    }
+}
+
+/// Hash of a leaf holding the concatenation of `parts`.
+///
+/// Shared with the state tree, so both Merkle trees keep leaves and inner
+/// nodes apart the same way.
+pub(crate) fn hash_leaf(parts: &[&[u8]]) -> BlockHash {
+    let mut hasher = Sha3_256::new();
+    hasher.update([LEAF_PREFIX]);
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize_fixed().into()
+}
+
+pub(crate) fn hash_node(left: &BlockHash, right: &BlockHash) -> BlockHash {
+    let mut hasher = Sha3_256::new();
+    hasher.update([NODE_PREFIX]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize_fixed().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(n: u8) -> Vec<TransactionId> {
+        (0..n).map(|i| TransactionId::from([i; 32])).collect()
+    }
+
+    #[test]
+    fn every_leaf_proves_against_the_root() {
+        for n in [0, 1, 2, 3, 5] {
+            let ids = ids(n);
+            let tree = MerkleTree::new(&ids);
+            let root = tree.root();
+
+            for (position, id) in ids.iter().enumerate() {
+                let proof = tree.proof(position).unwrap();
+                assert!(proof.verify(id, &root), "leaf {position} of {n}");
+                for (other, wrong) in ids.iter().enumerate() {
+                    if other != position {
+                        assert!(!proof.verify(wrong, &root));
+                    }
+                }
+            }
+            assert_eq!(tree.proof(ids.len()), None, "{n} leaves");
+        }
+    }
+
+    #[test]
+    fn small_trees_have_the_expected_shape() {
+        let ids = ids(3);
+        let leaves: Vec<BlockHash> = ids.iter().map(|id| hash_leaf(&[id.as_ref()])).collect();
+
+        assert_eq!(MerkleTree::new(&[]).root(), BlockHash::ZERO);
+        assert_eq!(MerkleTree::new(&ids[..1]).root(), leaves[0]);
+        assert!(
+            MerkleTree::new(&ids[..1])
+                .proof(0)
+                .unwrap()
+                .steps
+                .is_empty()
+        );
+
+        // The odd leaf is promoted, not paired with itself.
+        let root = hash_node(&hash_node(&leaves[0], &leaves[1]), &leaves[2]);
+        let tree = MerkleTree::new(&ids);
+        assert_eq!(tree.root(), root);
+        assert_eq!(
+            tree.proof(2).unwrap().steps,
+            vec![MerkleStep {
+                sibling: hash_node(&leaves[0], &leaves[1]),
+                left: true,
+            }]
+        );
+    }
+
+    #[test]
+    fn tampered_proofs_fail() {
+        let ids = ids(5);
+        let tree = MerkleTree::new(&ids);
+        let root = tree.root();
+
+        for (position, id) in ids.iter().enumerate() {
+            let proof = tree.proof(position).unwrap();
+            for step in 0..proof.steps.len() {
+                let mut flipped = proof.clone();
+                flipped.steps[step].left ^= true;
+                assert!(!flipped.verify(id, &root), "leaf {position} step {step}");
+
+                let mut replaced = proof.clone();
+                replaced.steps[step].sibling = BlockHash::from([0xff; 32]);
+                assert!(!replaced.verify(id, &root), "leaf {position} step {step}");
+            }
+
+            let mut truncated = proof.clone();
+            truncated.steps.pop();
+            assert!(!truncated.verify(id, &root));
+        }
+    }
+}
@@ -0,0 +1,287 @@
+use std::ops::Range;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+
+use rayon::prelude::*;
+
+use super::error::BlockError;
+use super::hash::BlockHash;
+use super::header::BlockHeader;
+
+/// Number of nonces tried between two cancellation and progress checks.
+const CHUNK_SIZE: u64 = 1 << 16;
+
+/// Handle used to stop a running [`Miner`] from another thread.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Release);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+}
+
+/// Progress counters shared between a [`Miner`] and its observers.
+#[derive(Debug, Clone)]
+pub struct MiningStats {
+    hashes: Arc<AtomicU64>,
+    started: Instant,
+}
+
+impl Default for MiningStats {
+    fn default() -> Self {
+        Self {
+            hashes: Arc::new(AtomicU64::new(0)),
+            started: Instant::now(),
+        }
+    }
+}
+
+impl MiningStats {
+    /// Hashes tried since the miner was created.
+    pub fn hashes(&self) -> u64 {
+        self.hashes.load(Ordering::Relaxed)
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Average hashes per second since the miner was created.
+    pub fn hash_rate(&self) -> f64 {
+        let elapsed = self.elapsed().as_secs_f64();
+        if elapsed == 0.0 {
+            return 0.0;
+        }
+        self.hashes() as f64 / elapsed
+    }
+
+    fn record(&self, hashes: u64) {
+        self.hashes.fetch_add(hashes, Ordering::Relaxed);
+    }
+}
+
+/// Proof-of-work search over a range of nonces.
+///
+/// Nonces are tried in order, so the lowest valid nonce in the range wins.
+/// When the range runs out, the miner either fails with
+/// [`BlockError::NonceTooHard`] or, if timestamp refresh is enabled, moves the
+/// header timestamp forward and searches the range again. An empty range is
+/// refused with [`BlockError::EmptyNonceRange`].
+#[derive(Debug, Clone)]
+pub struct Miner {
+    nonces: Range<u64>,
+    refresh_timestamp: bool,
+    cancel: CancellationToken,
+    stats: MiningStats,
+}
+
+impl Default for Miner {
+    fn default() -> Self {
+        Self {
+            nonces: 0..1_000_000_000,
+            refresh_timestamp: false,
+            cancel: CancellationToken::new(),
+            stats: MiningStats::default(),
+        }
+    }
+}
+
+impl Miner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_nonces(mut self, nonces: Range<u64>) -> Self {
+        self.nonces = nonces;
+        self
+    }
+
+    pub fn with_timestamp_refresh(mut self, refresh_timestamp: bool) -> Self {
+        self.refresh_timestamp = refresh_timestamp;
+        self
+    }
+
+    pub fn with_cancellation_token(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    pub fn stats(&self) -> MiningStats {
+        self.stats.clone()
+    }
+
+    /// Searches for a nonce meeting `header.difficulty` and returns the
+    /// completed header with its hash.
+    pub fn mine(&self, mut header: BlockHeader) -> Result<(BlockHeader, BlockHash), BlockError> {
+        if self.nonces.is_empty() {
+            return Err(BlockError::EmptyNonceRange);
+        }
+
+        loop {
+            if self.cancel.is_cancelled() {
+                return Err(BlockError::MiningCancelled);
+            }
+
+            if let Some((nonce, hash)) = self.search(&header)? {
+                header.nonce = nonce;
+                return Ok((header, hash));
+            }
+
+            if !self.refresh_timestamp {
+                return Err(BlockError::NonceTooHard);
+            }
+
+            let now = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_millis();
+            header.timestamp = now.max(header.timestamp + 1);
+        }
+    }
+
+    fn search(&self, header: &BlockHeader) -> Result<Option<(u64, BlockHash)>, BlockError> {
+        let base_hasher = header.hasher();
+        let difficulty = header.difficulty as usize;
+
+        let mut start = self.nonces.start;
+        while start < self.nonces.end {
+            if self.cancel.is_cancelled() {
+                return Err(BlockError::MiningCancelled);
+            }
+
+            let end = start.saturating_add(CHUNK_SIZE).min(self.nonces.end);
+            let found = (start..end).into_par_iter().find_map_first(|nonce| {
+                let hash = base_hasher.hash_nonce(nonce);
+                (hash.difficulty() >= difficulty).then_some((nonce, hash))
+            });
+
+            match found {
+                Some((nonce, hash)) => {
+                    self.stats.record(nonce - start + 1);
+                    return Ok(Some((nonce, hash)));
+                }
+                None => self.stats.record(end - start),
+            }
+
+            start = end;
+        }
+
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(difficulty: u32) -> BlockHeader {
+        BlockHeader {
+            index: 1,
+            timestamp: 1_700_000_000_000,
+            previous_hash: BlockHash::from([1u8; 32]),
+            transactions_root: BlockHash::from([2u8; 32]),
+            state_root: BlockHash::from([3u8; 32]),
+            difficulty,
+            nonce: 0,
+        }
+    }
+
+    #[test]
+    fn finds_the_lowest_valid_nonce_in_range() {
+        let header = header(6);
+        let miner = Miner::new().with_nonces(1_000..1_000_000);
+        let (mined, hash) = miner.mine(header).unwrap();
+
+        assert!((1_000..1_000_000).contains(&mined.nonce));
+        assert_eq!(mined.hash(), hash);
+        assert!(hash.difficulty() >= 6);
+        let hasher = header.hasher();
+        assert!((1_000..mined.nonce).all(|nonce| hasher.hash_nonce(nonce).difficulty() < 6));
+        assert_eq!(mined.timestamp, header.timestamp);
+
+        let stats = miner.stats();
+        assert_eq!(stats.hashes(), mined.nonce - 1_000 + 1);
+        assert!(stats.hash_rate() >= 0.0);
+    }
+
+    #[test]
+    fn exhausted_range_fails_without_refresh() {
+        let miner = Miner::new().with_nonces(0..16);
+        assert!(matches!(
+            miner.mine(header(255)),
+            Err(BlockError::NonceTooHard)
+        ));
+        assert_eq!(miner.stats().hashes(), 16);
+    }
+
+    #[test]
+    fn timestamp_refresh_searches_the_range_again() {
+        let header = header(8);
+        let miner = Miner::new().with_nonces(0..4).with_timestamp_refresh(true);
+        let (mined, hash) = miner.mine(header).unwrap();
+
+        assert!(mined.nonce < 4);
+        assert!(mined.timestamp > header.timestamp);
+        assert_eq!(mined.hash(), hash);
+        assert!(hash.difficulty() >= 8);
+        let hashes = miner.stats().hashes();
+        assert!(hashes > 4);
+        assert_eq!((hashes - (mined.nonce + 1)) % 4, 0);
+    }
+
+    #[test]
+    fn empty_range_is_rejected() {
+        let miner = Miner::new().with_nonces(5..5).with_timestamp_refresh(true);
+        assert!(matches!(
+            miner.mine(header(1)),
+            Err(BlockError::EmptyNonceRange)
+        ));
+    }
+
+    #[test]
+    fn cancelled_miner_stops() {
+        let cancel = CancellationToken::new();
+        let miner = Miner::new()
+            .with_timestamp_refresh(true)
+            .with_cancellation_token(cancel.clone());
+        cancel.cancel();
+
+        assert!(miner.cancellation_token().is_cancelled());
+        assert!(matches!(
+            miner.mine(header(255)),
+            Err(BlockError::MiningCancelled)
+        ));
+        assert_eq!(miner.stats().hashes(), 0);
+    }
+
+    #[test]
+    fn cancellation_stops_a_running_search() {
+        let miner = Miner::new().with_timestamp_refresh(true);
+        let cancel = miner.cancellation_token();
+        let stats = miner.stats();
+        let search = std::thread::spawn(move || miner.mine(header(255)));
+
+        while stats.hashes() == 0 {
+            std::thread::yield_now();
+        }
+        cancel.cancel();
+        assert!(matches!(
+            search.join().unwrap(),
+            Err(BlockError::MiningCancelled)
+        ));
+    }
+}
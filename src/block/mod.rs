@@ -1,6 +1,14 @@
 mod block;
 mod error;
 mod hash;
+mod header;
+mod merkle;
+mod miner;
 
 pub use block::Block;
-pub use error::BlockError;
+pub use error::{BlockError, BlockHashParseError};
+pub use hash::BlockHash;
+pub use header::BlockHeader;
+pub use merkle::{MerkleProof, MerkleStep, MerkleTree};
+pub(crate) use merkle::{hash_leaf, hash_node};
+pub use miner::{CancellationToken, Miner, MiningStats};
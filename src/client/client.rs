@@ -1,5 +1,5 @@
 use bincode::{Decode, Encode};
-use pqcrypto::sign::falcon512::keypair;
+use pqcrypto::sign::falconpadded512::keypair;
 use pqcrypto::traits::sign::{PublicKey, SecretKey};
 use std::fs;
 
@@ -16,6 +16,12 @@ pub struct Client {
     address: Address,
 }
 
+impl Default for Client {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Client {
     pub fn new() -> Self {
         let (pk, sk) = keypair();
@@ -45,7 +51,7 @@ impl Client {
     }
 
     pub fn save(&self) -> Result<(), ClientError> {
-        let encoded = bincode::encode_to_vec(&self, bincode::config::standard())?;
+        let encoded = bincode::encode_to_vec(self, bincode::config::standard())?;
         fs::write(DEFAULT_CREDS_LOCATION, encoded).map_err(ClientError::IOError)
     }
 }
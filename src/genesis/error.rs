@@ -0,0 +1,21 @@
+use thiserror::Error;
+
+use crate::account::AddressParseError;
+use crate::block::{BlockError, BlockHash};
+
+#[derive(Error, Debug)]
+pub enum GenesisError {
+    #[error("InvalidGenesisHash: got: {got}, want: {want}")]
+    InvalidGenesisHash { got: BlockHash, want: String },
+    #[error("AllocationOverflow: allocations add up to more than u64::MAX")]
+    AllocationOverflow,
+    #[error("AllocationAddressError: {0}")]
+    AllocationAddressError(#[from] AddressParseError),
+    #[error("BlockError: {0}")]
+    BlockError(#[from] BlockError),
+
+    #[error("IOError: {0}")]
+    IOError(#[from] std::io::Error),
+    #[error("ParseError: {0}")]
+    ParseError(#[from] toml::de::Error),
+}
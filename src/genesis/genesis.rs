@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use bincode::{Decode, Encode};
+use serde::{Deserialize, Serialize};
+
+use crate::account::{Account, Address, state_root};
+use crate::block::{Block, BlockHash};
+use crate::transaction::{ChainId, DEFAULT_CHAIN_ID, Transaction};
+
+use super::error::GenesisError;
+
+/// Difficulty of the default genesis block, in leading zero bits of the block hash.
+pub const INITIAL_DIFFICULTY: u32 = 8;
+
+/// Hash of the default genesis block, hex encoded.
+///
+/// Test vector for the canonical header hash: index 0, timestamp 0, a zero
+/// previous hash and a single mint of `u64::MAX / 2` to
+/// `9JEuZSy4CmRM8wMiE368Bx5jkgK5SLH1KvRDiUcNRjsV`, with the state root of
+/// that one account, mined at [`INITIAL_DIFFICULTY`] with the lowest valid
+/// nonce.
+pub const DEFAULT_GENESIS_HASH: &str =
+    "006f1953043a5da559a4a087cd5e30e9cb4aab4d069d4be0ecc029ec031a9761";
+
+/// Network definition the first block of a chain is derived from.
+///
+/// Loaded from a TOML file such as:
+///
+/// ```toml
+/// chain_id = 1
+/// timestamp = 0
+/// difficulty = 8
+/// genesis_hash = "006f1953043a5da559a4a087cd5e30e9cb4aab4d069d4be0ecc029ec031a9761"
+///
+/// [[allocations]]
+/// address = "9JEuZSy4CmRM8wMiE368Bx5jkgK5SLH1KvRDiUcNRjsV"
+/// amount = 9223372036854775807
+/// ```
+///
+/// The same file may hold the network's consensus rules in a `[consensus]`
+/// table, read by [`ConsensusParams::from_file`](crate::ledger::ConsensusParams::from_file).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Encode, Decode)]
+pub struct GenesisConfig {
+    pub chain_id: ChainId,
+    /// Genesis block timestamp, in milliseconds since the Unix epoch.
+    pub timestamp: u64,
+    pub difficulty: u32,
+    /// Expected hash of the genesis block, hex encoded.
+    pub genesis_hash: String,
+    pub allocations: Vec<Allocation>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Encode, Decode)]
+pub struct Allocation {
+    /// Base58 encoded address.
+    pub address: String,
+    pub amount: u64,
+}
+
+impl Default for GenesisConfig {
+    fn default() -> Self {
+        Self {
+            chain_id: DEFAULT_CHAIN_ID,
+            timestamp: 0,
+            difficulty: INITIAL_DIFFICULTY,
+            genesis_hash: DEFAULT_GENESIS_HASH.to_string(),
+            allocations: vec![Allocation {
+                address: "9JEuZSy4CmRM8wMiE368Bx5jkgK5SLH1KvRDiUcNRjsV".to_string(),
+                amount: u64::MAX / 2,
+            }],
+        }
+    }
+}
+
+impl GenesisConfig {
+    /// Sum of all allocations, or `None` if it does not fit in a `u64`.
+    pub fn total_allocation(&self) -> Option<u64> {
+        self.allocations.iter().try_fold(0u64, |total, allocation| {
+            total.checked_add(allocation.amount)
+        })
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, GenesisError> {
+        let contents = fs::read_to_string(path)?;
+        Ok(toml::from_str(&contents)?)
+    }
+
+    /// Mines the genesis block, minting every allocation, and checks its hash
+    /// against `genesis_hash`. Allocations must not add up to more than
+    /// `u64::MAX`.
+    pub fn block(&self) -> Result<Block, GenesisError> {
+        if self.total_allocation().is_none() {
+            return Err(GenesisError::AllocationOverflow);
+        }
+
+        let transactions = self
+            .allocations
+            .iter()
+            .map(|allocation| {
+                let to_address = Address::try_from(allocation.address.as_str())?;
+                Ok(Transaction::mint(to_address, allocation.amount, 0))
+            })
+            .collect::<Result<Vec<_>, GenesisError>>()?;
+
+        // The total fits in a u64, so no single balance can overflow.
+        let mut accounts: HashMap<Address, Account> = HashMap::new();
+        for t in &transactions {
+            accounts
+                .entry(t.to_address)
+                .or_insert_with(|| Account::new(t.to_address))
+                .balance += t.amount;
+        }
+
+        let genesis = Block::forge(
+            0,
+            self.timestamp.into(),
+            BlockHash::from([0u8; 32]),
+            transactions,
+            state_root(accounts.values()),
+            self.difficulty,
+        )?;
+
+        if genesis.hash().to_string() != self.genesis_hash {
+            return Err(GenesisError::InvalidGenesisHash {
+                got: *genesis.hash(),
+                want: self.genesis_hash.clone(),
+            });
+        }
+
+        Ok(genesis)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_genesis_matches_vector() {
+        let genesis = GenesisConfig::default().block().unwrap();
+        let header = genesis.header();
+
+        assert_eq!(genesis.hash().to_string(), DEFAULT_GENESIS_HASH);
+        assert_eq!(header.index, 0);
+        assert_eq!(header.timestamp, 0);
+        assert_eq!(header.previous_hash, BlockHash::ZERO);
+        assert_eq!(header.difficulty, INITIAL_DIFFICULTY);
+        assert_eq!(header.hash(), *genesis.hash());
+        genesis.verify_hash().unwrap();
+    }
+
+    #[test]
+    fn default_genesis_uses_lowest_valid_nonce() {
+        let genesis = GenesisConfig::default().block().unwrap();
+        let hasher = genesis.header().hasher();
+
+        for nonce in 0..genesis.header().nonce {
+            assert!(hasher.hash_nonce(nonce).difficulty() < INITIAL_DIFFICULTY as usize);
+        }
+    }
+
+    #[test]
+    fn genesis_file_matches_default() {
+        let config = GenesisConfig::from_file("genesis.toml").unwrap();
+        assert_eq!(config, GenesisConfig::default());
+    }
+
+    #[test]
+    fn genesis_hash_mismatch_is_rejected() {
+        let mut config = GenesisConfig::default();
+        config.timestamp += 1;
+
+        assert!(matches!(
+            config.block(),
+            Err(GenesisError::InvalidGenesisHash { .. })
+        ));
+    }
+}
@@ -0,0 +1,5 @@
+mod error;
+mod genesis;
+
+pub use error::GenesisError;
+pub use genesis::{Allocation, DEFAULT_GENESIS_HASH, GenesisConfig, INITIAL_DIFFICULTY};
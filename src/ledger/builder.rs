@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+
+use crate::account::{Account, Address};
+use crate::block::{Block, Miner};
+use crate::storage::Storage;
+use crate::transaction::{Transaction, TransactionError, TransactionType};
+
+use super::error::LedgerError;
+use super::ledger::{Ledger, apply_to_state};
+
+/// Outcome of dry-running candidate transactions for the next block.
+#[derive(Debug, Default)]
+pub struct BlockReport {
+    /// Transactions that fit in the block, in the order they were given.
+    pub accepted: Vec<Transaction>,
+    pub rejected: Vec<Rejection>,
+}
+
+/// A candidate left out of the block, with the reason it was refused.
+#[derive(Debug)]
+pub struct Rejection {
+    pub transaction: Transaction,
+    pub reason: LedgerError,
+}
+
+impl<S: Storage> Ledger<S> {
+    /// Dry-runs `candidates` in order on top of the current tip and splits
+    /// them into those a block may include and those it may not.
+    ///
+    /// Each candidate is checked against the state left by the candidates
+    /// accepted before it, so a sender's transactions must come in nonce order.
+    /// The ledger itself is not modified.
+    pub fn select_transactions(&self, candidates: Vec<Transaction>) -> BlockReport {
+        let mut scratch: HashMap<Address, Account> = HashMap::new();
+        let mut report = BlockReport::default();
+        let mut fees: u64 = 0;
+
+        for t in candidates {
+            match self.dry_run_candidate(&mut scratch, &t, fees) {
+                Ok(()) => {
+                    fees += t.fee;
+                    report.accepted.push(t);
+                }
+                Err(reason) => report.rejected.push(Rejection {
+                    transaction: t,
+                    reason,
+                }),
+            }
+        }
+
+        report
+    }
+
+    pub fn build_block(
+        &self,
+        producer: Address,
+        candidates: Vec<Transaction>,
+    ) -> Result<(Block, BlockReport), LedgerError> {
+        self.build_block_with(&Miner::default(), producer, candidates)
+    }
+
+    /// Mines a block on top of the current tip holding the valid transactions
+    /// among `candidates`, behind a coinbase paying `producer`.
+    pub fn build_block_with(
+        &self,
+        miner: &Miner,
+        producer: Address,
+        candidates: Vec<Transaction>,
+    ) -> Result<(Block, BlockReport), LedgerError> {
+        let report = self.select_transactions(candidates);
+
+        let mut transactions = vec![self.coinbase(producer, &report.accepted)?];
+        transactions.extend_from_slice(&report.accepted);
+        let block = self.forge_with(miner, transactions)?;
+
+        Ok((block, report))
+    }
+
+    /// Validates `t` against `scratch`, the accounts already touched by
+    /// accepted candidates, and applies it there if it is valid.
+    fn dry_run_candidate(
+        &self,
+        scratch: &mut HashMap<Address, Account>,
+        t: &Transaction,
+        fees: u64,
+    ) -> Result<(), LedgerError> {
+        if t.tx_type == TransactionType::Mint {
+            return Err(LedgerError::ForbiddenMintTransaction(t.id()));
+        }
+
+        let reward = self.params().subsidy(self.last()?.index() + 1);
+        if reward
+            .checked_add(fees)
+            .and_then(|r| r.checked_add(t.fee))
+            .is_none()
+        {
+            return Err(TransactionError::Overflow(t.id()).into());
+        }
+
+        // Apply to copies of the two accounts involved so that a failure
+        // halfway through leaves `scratch` untouched.
+        let mut touched = HashMap::new();
+        for address in [t.from_address, t.to_address] {
+            let account = match scratch.get(&address) {
+                Some(account) => *account,
+                None => self.account(address)?,
+            };
+            touched.insert(address, account);
+        }
+
+        let sender = touched[&t.from_address];
+        self.verify_transaction(t, &sender)?;
+        self.dry_run_transaction(t, &sender)?;
+
+        apply_to_state(&mut touched, t)?;
+        scratch.extend(touched);
+
+        Ok(())
+    }
+}
@@ -1,21 +1,59 @@
 use thiserror::Error;
 
-use crate::block::{Block, BlockError};
-use crate::transaction::{Transaction, TransactionError};
+use crate::account::Address;
+use crate::block::{BlockError, BlockHash};
+use crate::genesis::GenesisError;
+use crate::storage::StorageError;
+use crate::transaction::{TransactionError, TransactionId};
 
 #[derive(Error, Debug)]
 pub enum LedgerError {
     #[error("BlockError: {0}")]
     BlockError(#[from] BlockError),
-    #[error("GenesisBlockError: {0}")]
-    GenesisBlockError(Block),
+    #[error("GenesisBlockError: chain starts at {0} instead of the genesis block")]
+    GenesisBlockError(BlockHash),
+    #[error("GenesisError: {0}")]
+    GenesisError(#[from] GenesisError),
+    #[error("GenesisMismatch: storage holds a chain that does not start at genesis block {0}")]
+    GenesisMismatch(BlockHash),
     #[error("BlockNotFoundError: index:{0}")]
     BlockNotFound(u64),
+    #[error("UnknownParent: no known block with hash {0}")]
+    UnknownParent(BlockHash),
+    #[error("DuplicateBlock: block {0} is already known")]
+    DuplicateBlock(BlockHash),
+    #[error("InvalidBlock: block {index} failed validation: {source}")]
+    InvalidBlock {
+        index: u64,
+        #[source]
+        source: Box<LedgerError>,
+    },
+    #[error("GenesisConfigMismatch: encoded ledger was built from another genesis configuration")]
+    GenesisConfigMismatch,
+    #[error("ParamsMismatch: ledger was built under other consensus parameters")]
+    ParamsMismatch,
+    #[error("StateMismatch: stored state differs from the replayed chain state")]
+    StateMismatch,
+    #[error("EmptySnapshot: snapshot holds no block")]
+    EmptySnapshot,
+    #[error("DuplicateAccount: snapshot holds more than one record for {0}")]
+    DuplicateAccount(Address),
+    #[error("NonEmptyStorage: cannot start from a snapshot over an existing chain")]
+    NonEmptyStorage,
 
     #[error("TransactionError: {0}")]
     TransactionError(#[from] TransactionError),
-    #[error("ForbiddenMintTransaction: mint transaction outside of genesis block: {0:?}")]
-    ForbiddenMintTransaction(Transaction),
+    #[error("ForbiddenMintTransaction: mint transaction {0} outside of the coinbase")]
+    ForbiddenMintTransaction(TransactionId),
+    #[error(
+        "InvalidCoinbase: coinbase must mint from the zero address with the block index as nonce and no fee: {0}"
+    )]
+    InvalidCoinbase(TransactionId),
+    #[error("InvalidCoinbaseAmount: got: {got}, want: {want}")]
+    InvalidCoinbaseAmount { got: u64, want: u64 },
+
+    #[error("StorageError: {0}")]
+    StorageError(#[from] StorageError),
 
     #[error("EncodeError: {0}")]
     EncodeError(#[from] bincode::error::EncodeError),
@@ -1,91 +1,690 @@
-use crate::account::Address;
-use crate::block::Block;
-use crate::transaction::{self, Transaction, TransactionError, TransactionType};
+use crate::account::{Account, AccountProof, Address, NodeKey, StateNode, StateTree};
+use crate::block::{Block, BlockError, BlockHash, Miner};
+use crate::genesis::GenesisConfig;
+use crate::storage::{MemoryStorage, StateUndo, Storage, StorageError};
+use crate::transaction::{
+    self, ChainId, Transaction, TransactionError, TransactionId, TransactionType,
+};
 
 use super::error::LedgerError;
+use super::params::ConsensusParams;
+use super::snapshot::Snapshot;
 
 use bincode::{Decode, Encode, config};
 use std::collections::HashMap;
 use std::time::SystemTime;
 
-pub const TRANSACTION_COST: u64 = 0;
+pub const MAX_FUTURE_DRIFT_MS: u128 = 2 * 60 * 1000;
 
+/// Chain state following the branch with the most cumulative proof of work.
+///
+/// The active branch runs from height `base`, which is zero unless the ledger
+/// was bootstrapped from a snapshot, to `height`. Everything else is kept in
+/// `storage`, which is committed each time the ledger settles on a tip: every
+/// known block with the cumulative work of its branch, the hashes and
+/// transactions of the active branch, account state, the state tree over it
+/// and the undo record of every active block above `base`. Side branches are
+/// kept until they become heavier than the active one, at which point the
+/// ledger reorganises onto them. `supply` is the sum of every balance, kept up
+/// to date as accounts change.
 #[derive(Debug, Clone, Encode, Decode)]
-pub struct Ledger {
-    chain: Vec<Block>,
-    state: HashMap<Address, u64>,
+pub struct Ledger<S = MemoryStorage> {
+    genesis: GenesisConfig,
+    params: ConsensusParams,
+    base: u64,
+    height: u64,
+    pending: Vec<Transaction>,
+    supply: u128,
+    storage: S,
 }
 
 impl Ledger {
     pub fn new() -> Result<Self, LedgerError> {
-        let mut ledger = Ledger {
-            chain: Vec::new(),
-            state: HashMap::new(),
-        };
+        Self::with_params(ConsensusParams::default())
+    }
+
+    pub fn with_params(params: ConsensusParams) -> Result<Self, LedgerError> {
+        Self::from_genesis(GenesisConfig::default(), params)
+    }
+
+    /// Starts a chain in memory from the genesis block described by `genesis`.
+    pub fn from_genesis(
+        genesis: GenesisConfig,
+        params: ConsensusParams,
+    ) -> Result<Self, LedgerError> {
+        Self::open(MemoryStorage::new(), genesis, params)
+    }
+
+    /// Decodes a ledger and re-validates it by replaying every block from
+    /// `genesis` under `params`.
+    ///
+    /// The encoding carries its own genesis configuration and consensus
+    /// parameters, which must match the ones given: they come from whoever
+    /// wrote the bytes and cannot be trusted on their own.
+    pub fn from_bytes(
+        bytes: Vec<u8>,
+        genesis: GenesisConfig,
+        params: ConsensusParams,
+    ) -> Result<Self, LedgerError> {
+        let config = config::standard();
+        let (decoded, _): (Self, usize) = bincode::decode_from_slice(&bytes, config)?;
+        if decoded.genesis != genesis {
+            return Err(LedgerError::GenesisConfigMismatch);
+        }
+        if decoded.params != params {
+            return Err(LedgerError::ParamsMismatch);
+        }
+
+        let state = Self::state(&decoded.storage)?;
+        let ledger = Self::replay(decoded, genesis, params)?;
+        if Self::state(&ledger.storage)? != state {
+            return Err(LedgerError::StateMismatch);
+        }
+
+        Ok(ledger)
+    }
+
+    fn state(storage: &MemoryStorage) -> Result<HashMap<Address, Account>, LedgerError> {
+        Ok(storage
+            .accounts()?
+            .into_iter()
+            .map(|account| (account.address(), account))
+            .collect())
+    }
+
+    /// Rebuilds a ledger from scratch by appending every block of `decoded`'s
+    /// active chain on top of the block of `genesis`, under `params`.
+    fn replay(
+        decoded: Self,
+        genesis: GenesisConfig,
+        params: ConsensusParams,
+    ) -> Result<Self, LedgerError> {
+        let first = decoded
+            .block_at(decoded.base)?
+            .ok_or(LedgerError::BlockNotFound(decoded.base))?;
+
+        let mut ledger = Self::from_genesis(genesis, params)?;
+        if first != ledger.last()? {
+            return Err(LedgerError::GenesisBlockError(*first.hash()));
+        }
+
+        for height in decoded.base + 1..=decoded.height {
+            let block = decoded
+                .block_at(height)?
+                .ok_or(LedgerError::BlockNotFound(height))?;
+            let index = block.index();
+            let tip = ledger.tip()?;
+            if *block.previous_hash() != tip {
+                return Err(LedgerError::InvalidBlock {
+                    index,
+                    source: Box::new(
+                        BlockError::InvalidPreviousHash {
+                            got: *block.previous_hash(),
+                            want: tip,
+                        }
+                        .into(),
+                    ),
+                });
+            }
+
+            ledger
+                .append_block(block)
+                .map_err(|e| LedgerError::InvalidBlock {
+                    index,
+                    source: Box::new(e),
+                })?;
+        }
+
+        Ok(ledger)
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, LedgerError> {
+        let config = config::standard();
+        bincode::encode_to_vec(self, config).map_err(LedgerError::from)
+    }
+}
+
+impl<S: Storage> Ledger<S> {
+    /// Opens the chain kept in `storage` at its last committed tip, or starts
+    /// one from the genesis block described by `genesis` if `storage` is empty.
+    pub fn open(
+        storage: S,
+        genesis: GenesisConfig,
+        params: ConsensusParams,
+    ) -> Result<Self, LedgerError> {
+        let mut ledger = Self::empty(storage, genesis, params);
 
-        ledger.genesis()?;
+        match ledger.storage.committed_tip() {
+            Some(tip) => {
+                ledger.check_params(tip)?;
+                ledger.load(tip)?;
+            }
+            None => ledger.genesis()?,
+        }
 
         Ok(ledger)
     }
 
+    /// Starts a chain in the empty `storage` from `snapshot` rather than from
+    /// genesis, once [`Snapshot::verify`] accepts it. The blocks that follow
+    /// the snapshot are then appended as usual.
+    ///
+    /// The ledger can never reorganise below the snapshot block, and
+    /// `genesis` only provides the chain ID.
+    pub fn from_snapshot(
+        storage: S,
+        genesis: GenesisConfig,
+        params: ConsensusParams,
+        snapshot: Snapshot,
+    ) -> Result<Self, LedgerError> {
+        snapshot.verify()?;
+        if storage.committed_tip().is_some() {
+            return Err(LedgerError::NonEmptyStorage);
+        }
+
+        let mut ledger = Self::empty(storage, genesis, params);
+        let Snapshot { blocks, accounts } = snapshot;
+        for block in &blocks {
+            ledger.storage.put_block(block)?;
+        }
+        for account in accounts {
+            ledger.set_account(account.address(), Some(account))?;
+        }
+
+        let block = blocks.last().ok_or(LedgerError::EmptySnapshot)?;
+        ledger.storage.put_params(ledger.params)?;
+        ledger.insert_work(block)?;
+        ledger.base = block.index();
+        ledger.storage.put_base(ledger.base)?;
+        ledger.index_block(block)?;
+        ledger.storage.commit(block.hash())?;
+
+        Ok(ledger)
+    }
+
+    fn empty(storage: S, genesis: GenesisConfig, params: ConsensusParams) -> Self {
+        Ledger {
+            genesis,
+            params,
+            base: 0,
+            height: 0,
+            pending: Vec::new(),
+            supply: 0,
+            storage,
+        }
+    }
+
     fn genesis(&mut self) -> Result<(), LedgerError> {
-        let genesis = Block::genesis()?;
+        let genesis = self.genesis.block()?;
 
         for t in genesis.transactions() {
             self.apply_transaction_unchecked(t)?;
         }
 
-        self.chain.push(genesis);
+        self.insert_work(&genesis)?;
+        self.storage.put_block(&genesis)?;
+        self.push_block(&genesis, StateUndo::new())?;
+        self.storage.put_base(0)?;
+        self.storage.put_params(self.params)?;
+        self.storage.commit(genesis.hash())?;
 
         Ok(())
     }
 
-    pub fn balance(&self, address: Address) -> u64 {
-        self.state.get(&address).copied().unwrap_or(0)
+    /// Checks that the stored chain was validated under the ledger's consensus
+    /// parameters, recording them for storage written before they were kept.
+    fn check_params(&mut self, tip: BlockHash) -> Result<(), LedgerError> {
+        match self.storage.params()? {
+            Some(params) if params != self.params => Err(LedgerError::ParamsMismatch),
+            Some(_) => Ok(()),
+            None => {
+                self.storage.put_params(self.params)?;
+                self.storage.commit(&tip)?;
+                Ok(())
+            }
+        }
     }
 
-    pub fn state(&self) -> HashMap<Address, u64> {
-        self.state.clone()
+    /// Resumes from `tip`, whose state is already in storage and must match
+    /// its state root.
+    ///
+    /// Only the tip is read back: the active chain, block work and transaction
+    /// index were committed with it.
+    fn load(&mut self, tip: BlockHash) -> Result<(), LedgerError> {
+        let block = self.stored_block(&tip)?;
+        self.base = self.storage.base()?.ok_or(LedgerError::StateMismatch)?;
+        self.height = block.index();
+        if self.active_hash(self.height)? != Some(tip) {
+            return Err(LedgerError::StateMismatch);
+        }
+
+        let genesis = *self.genesis.block()?.hash();
+        if self.base == 0 && self.active_hash(0)? != Some(genesis) {
+            return Err(LedgerError::GenesisMismatch(genesis));
+        }
+        if *block.state_root() != self.state_root()? {
+            return Err(LedgerError::StateMismatch);
+        }
+        self.supply = self
+            .storage
+            .accounts()?
+            .iter()
+            .map(|a| a.balance as u128)
+            .sum();
+
+        Ok(())
     }
 
-    pub fn from_bytes(bytes: Vec<u8>) -> Result<Self, LedgerError> {
-        let config = config::standard();
-        let (decoded, _): (Self, usize) = bincode::decode_from_slice(&bytes, config)?;
-        // TODO: Add entire ledger re-validation from the start
-        Ok(decoded)
+    /// Account record for `address`, or an empty one if it never appeared on chain.
+    pub fn account(&self, address: Address) -> Result<Account, LedgerError> {
+        Ok(self
+            .storage
+            .account(&address)?
+            .unwrap_or_else(|| Account::new(address)))
+    }
+
+    pub fn balance(&self, address: Address) -> Result<u64, LedgerError> {
+        Ok(self.account(address)?.balance())
+    }
+
+    /// Nonce the next transaction sent from `address` must carry.
+    pub fn nonce(&self, address: Address) -> Result<u64, LedgerError> {
+        Ok(self.account(address)?.nonce())
+    }
+
+    pub fn chain_id(&self) -> ChainId {
+        self.genesis.chain_id
+    }
+
+    pub fn genesis_config(&self) -> &GenesisConfig {
+        &self.genesis
+    }
+
+    pub fn params(&self) -> &ConsensusParams {
+        &self.params
+    }
+
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    /// Any known block, on the active chain or on a side branch.
+    pub fn block(&self, hash: &BlockHash) -> Result<Option<Block>, LedgerError> {
+        if self.storage.work(hash)?.is_none() {
+            return Ok(None);
+        }
+
+        Ok(self.storage.block(hash)?)
+    }
+
+    /// Block at height `index` of the active chain.
+    pub fn block_at(&self, index: u64) -> Result<Option<Block>, LedgerError> {
+        match self.active_hash(index)? {
+            Some(hash) => Ok(self.storage.block(&hash)?),
+            None => Ok(None),
+        }
+    }
+
+    /// Block on the active chain including transaction `id`, with its position
+    /// in the block.
+    pub fn find_transaction(
+        &self,
+        id: &TransactionId,
+    ) -> Result<Option<(Block, usize)>, LedgerError> {
+        let Some((index, position)) = self.storage.transaction(id)? else {
+            return Ok(None);
+        };
+
+        Ok(self.block_at(index)?.map(|block| (block, position)))
+    }
+
+    /// Cumulative proof of work of the active chain, counted from its first
+    /// block.
+    pub fn work(&self) -> Result<u128, LedgerError> {
+        self.storage
+            .work(&self.tip()?)?
+            .ok_or(LedgerError::BlockNotFound(self.height))
+    }
+
+    /// Height of the tip of the active chain.
+    pub fn height(&self) -> u64 {
+        self.height
+    }
+
+    /// Height of the first block of the active chain: zero, or the height of
+    /// the snapshot the ledger was bootstrapped from.
+    pub fn base(&self) -> u64 {
+        self.base
+    }
+
+    /// Transactions orphaned by a reorganisation that are not on the active chain.
+    pub fn pending(&self) -> &[Transaction] {
+        &self.pending
+    }
+
+    /// Commitment to the account state at the tip of the active chain.
+    pub fn state_root(&self) -> Result<BlockHash, LedgerError> {
+        Ok(self.state_tree().root()?)
+    }
+
+    /// Every account record at height `index` of the active chain, rolling the
+    /// current state back with the undo records of the blocks above it.
+    pub fn accounts_at(&self, index: u64) -> Result<Vec<Account>, LedgerError> {
+        if index < self.base || index > self.height() {
+            return Err(LedgerError::BlockNotFound(index));
+        }
+
+        let mut accounts: HashMap<Address, Account> = self
+            .storage
+            .accounts()?
+            .into_iter()
+            .map(|account| (account.address(), account))
+            .collect();
+        for height in (index + 1..=self.height()).rev() {
+            for (address, account) in self.active_undo(height)? {
+                match account {
+                    Some(account) => accounts.insert(address, account),
+                    None => accounts.remove(&address),
+                };
+            }
+        }
+
+        Ok(accounts.into_values().collect())
+    }
+
+    /// Record of `address` at height `index` of the active chain, if it has
+    /// one, with a proof against the state root of the block at that height.
+    ///
+    /// Only the paths of the accounts changed by the blocks above `index` are
+    /// rolled back to build the proof.
+    pub fn account_proof(
+        &self,
+        address: Address,
+        index: u64,
+    ) -> Result<(Option<Account>, AccountProof), LedgerError> {
+        if index < self.base || index > self.height() {
+            return Err(LedgerError::BlockNotFound(index));
+        }
+
+        let mut tree = self.state_tree();
+        let mut account = self.storage.account(&address)?;
+        for height in (index + 1..=self.height()).rev() {
+            for (touched, previous) in self.active_undo(height)? {
+                match previous {
+                    Some(previous) => tree.insert(&previous)?,
+                    None => tree.remove(&touched)?,
+                }
+                if touched == address {
+                    account = previous;
+                }
+            }
+        }
+
+        Ok((account, tree.proof(&address)?))
+    }
+
+    /// Commitment to the account state once `transactions` are applied on top
+    /// of the tip, without validating them.
+    pub fn state_root_after(&self, transactions: &[Transaction]) -> Result<BlockHash, LedgerError> {
+        let mut touched = HashMap::new();
+        for t in transactions {
+            for address in [t.from_address, t.to_address] {
+                if !touched.contains_key(&address)
+                    && let Some(account) = self.storage.account(&address)?
+                {
+                    touched.insert(address, account);
+                }
+            }
+            apply_to_state(&mut touched, t)?;
+        }
+
+        let mut tree = self.state_tree();
+        for account in touched.values() {
+            tree.insert(account)?;
+        }
+
+        Ok(tree.root()?)
+    }
+
+    /// Upper bound on the coins that can ever exist: the genesis allocations
+    /// plus every block subsidy.
+    pub fn supply_cap(&self) -> u128 {
+        let allocated: u128 = self
+            .genesis
+            .allocations
+            .iter()
+            .map(|allocation| allocation.amount as u128)
+            .sum();
+
+        allocated.saturating_add(self.params.max_emission())
+    }
+
+    /// Sum of every account balance.
+    ///
+    /// Widened so that a broken invariant shows up as a supply above
+    /// [`Ledger::supply_cap`] rather than as an overflow.
+    pub fn total_supply(&self) -> u128 {
+        self.supply
+    }
+
+    /// Amount the coinbase of the block at `index` must pay: the block subsidy
+    /// plus the fees of `transactions`, the rest of the block.
+    ///
+    /// Fees are debited from senders when their transfers are applied, so a
+    /// block without a coinbase burns them.
+    pub fn block_reward(
+        &self,
+        index: u64,
+        transactions: &[Transaction],
+    ) -> Result<u64, TransactionError> {
+        transactions
+            .iter()
+            .try_fold(self.params.subsidy(index), |reward, t| {
+                reward
+                    .checked_add(t.fee)
+                    .ok_or(TransactionError::Overflow(t.id()))
+            })
+    }
+
+    /// Coinbase paying `producer` the reward for a block on top of the current
+    /// tip holding `transactions`.
+    pub fn coinbase(
+        &self,
+        producer: Address,
+        transactions: &[Transaction],
+    ) -> Result<Transaction, LedgerError> {
+        let index = self.last()?.index() + 1;
+        let reward = self.block_reward(index, transactions)?;
+
+        Ok(Transaction::mint(producer, reward, index))
+    }
+
+    pub fn take_pending(&mut self) -> Vec<Transaction> {
+        std::mem::take(&mut self.pending)
     }
 
     pub fn forge(&self, transactions: Vec<Transaction>) -> Result<Block, LedgerError> {
+        self.forge_with(&Miner::default(), transactions)
+    }
+
+    /// Mines a block on top of the current tip with `miner`, which the caller
+    /// can cancel once the tip moves.
+    pub fn forge_with(
+        &self,
+        miner: &Miner,
+        transactions: Vec<Transaction>,
+    ) -> Result<Block, LedgerError> {
         let last_block = self.last()?;
-        let timestamp = SystemTime::now()
+        let now = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
             .unwrap()
             .as_millis();
+        let timestamp = now.max(last_block.timestamp() + 1);
+        let difficulty = self.next_difficulty(&last_block)?;
+        let state_root = self.state_root_after(&transactions)?;
 
-        Block::forge(
+        Block::forge_with(
+            miner,
             last_block.index() + 1,
             timestamp,
-            last_block.hash().clone(),
+            *last_block.hash(),
             transactions,
+            state_root,
+            difficulty,
         )
         .map_err(LedgerError::from)
     }
 
-    pub fn apply_transactions(&mut self, block: &Block) -> Result<(), LedgerError> {
-        for t in block.transactions() {
-            if t.tx_type == TransactionType::Mint && block.index() != 0 {
-                return Err(LedgerError::ForbiddenMintTransaction(t.clone()));
+    /// Difficulty required of a block built on top of `parent`.
+    ///
+    /// Every `retarget_interval` blocks, difficulty is adjusted from the time
+    /// the previous interval took to mine. The first window is skipped since
+    /// the genesis timestamp is not a mining time.
+    pub fn next_difficulty(&self, parent: &Block) -> Result<u32, LedgerError> {
+        let index = parent.index() + 1;
+        let interval = self.params.retarget_interval;
+        if interval < 2 || !index.is_multiple_of(interval) || index <= interval {
+            return Ok(parent.difficulty());
+        }
+
+        let mut first = self.stored_block(parent.previous_hash())?;
+        for _ in 2..interval {
+            first = self.stored_block(first.previous_hash())?;
+        }
+
+        let actual = parent.timestamp().saturating_sub(first.timestamp());
+        let expected = self
+            .params
+            .target_block_time_ms
+            .saturating_mul((interval - 1) as u128);
+
+        Ok(self.params.retarget(parent.difficulty(), actual, expected))
+    }
+
+    /// Validates `block` and stores it.
+    ///
+    /// A block extending the tip is applied directly. A block on a side branch
+    /// is only stored, unless its branch now has more cumulative work than the
+    /// active chain, in which case the ledger reorganises onto it. Either way,
+    /// a block that fails validation leaves the ledger untouched.
+    pub fn append_block(&mut self, block: Block) -> Result<(), LedgerError> {
+        if self.storage.work(block.hash())?.is_some() {
+            return Err(LedgerError::DuplicateBlock(*block.hash()));
+        }
+
+        if self.storage.work(block.previous_hash())?.is_none() {
+            return Err(LedgerError::UnknownParent(*block.previous_hash()));
+        }
+        let parent = self.stored_block(block.previous_hash())?;
+        self.validate_header(&block, &parent)?;
+
+        let result = self.attach_block(block);
+        let tip = self.tip()?;
+        self.storage.commit(&tip)?;
+
+        result
+    }
+
+    fn attach_block(&mut self, block: Block) -> Result<(), LedgerError> {
+        if *block.previous_hash() == self.tip()? {
+            let undo = self.apply_transactions(&block)?;
+            self.pending.retain(|t| !block.transactions().contains(t));
+            self.insert_work(&block)?;
+            self.storage.put_block(&block)?;
+            self.push_block(&block, undo)?;
+
+            return Ok(());
+        }
+
+        let work = self.insert_work(&block)?;
+        self.storage.put_block(&block)?;
+        if work > self.work()? {
+            self.reorganize(*block.hash())?;
+        }
+
+        Ok(())
+    }
+
+    fn validate_header(&self, block: &Block, parent: &Block) -> Result<(), LedgerError> {
+        if block.index() != parent.index() + 1 {
+            return Err(BlockError::InvalidIndex {
+                got: block.index(),
+                want: parent.index() + 1,
+            }
+            .into());
+        }
+
+        let difficulty = self.next_difficulty(parent)?;
+        if block.difficulty() != difficulty {
+            return Err(BlockError::InvalidDifficulty {
+                got: block.difficulty(),
+                want: difficulty,
+            }
+            .into());
+        }
+
+        block.verify_hash()?;
+
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        if block.timestamp() <= parent.timestamp() || block.timestamp() > now + MAX_FUTURE_DRIFT_MS
+        {
+            return Err(BlockError::InvalidTimestamp {
+                got: block.timestamp(),
+                previous: parent.timestamp(),
             }
+            .into());
+        }
+
+        Ok(())
+    }
 
-            if let Err(e) = transaction::verify_signature(&t) {
-                return Err(e.into());
+    /// Switches the active chain to the branch ending at `tip`.
+    ///
+    /// State is rolled back to the fork point and the new branch replayed. If a
+    /// block of the new branch is invalid, it and its descendants are dropped
+    /// from storage, and the previous chain is restored.
+    fn reorganize(&mut self, tip: BlockHash) -> Result<(), LedgerError> {
+        let mut branch = Vec::new();
+        let mut cursor = self.stored_block(&tip)?;
+        while !self.is_active(&cursor)? {
+            let parent = self.stored_block(cursor.previous_hash())?;
+            branch.push(cursor);
+            cursor = parent;
+        }
+        branch.reverse();
+
+        let fork_index = cursor.index();
+        let detached = self.rollback_to(fork_index)?;
+
+        for (i, block) in branch.iter().enumerate() {
+            match self.apply_transactions(block) {
+                Ok(undo) => self.push_block(block, undo)?,
+                Err(e) => {
+                    for invalid in &branch[i..] {
+                        self.storage.remove_work(invalid.hash())?;
+                        self.storage.remove_block(invalid.hash())?;
+                    }
+
+                    self.rollback_to(fork_index)?;
+                    for block in detached {
+                        let undo = self.apply_transactions(&block)?;
+                        self.push_block(&block, undo)?;
+                    }
+
+                    return Err(e);
+                }
             }
+        }
 
-            // TODO: for now, entire block is refused if at least one transaction is invalid
-            match self.dry_run_transaction(t) {
-                Err(e) => return Err(LedgerError::TransactionError(e)),
-                Ok(_) => {
-                    self.apply_transaction_unchecked(t)?;
+        let included = |t: &Transaction| branch.iter().any(|b| b.transactions().contains(t));
+        self.pending.retain(|t| !included(t));
+        for block in &detached {
+            for t in block.transactions() {
+                if t.tx_type != TransactionType::Mint && !included(t) {
+                    self.pending.push(*t);
                 }
             }
         }
@@ -93,71 +692,1133 @@ impl Ledger {
         Ok(())
     }
 
-    fn dry_run_transaction(&self, t: &Transaction) -> Result<(), TransactionError> {
-        match self.state.get(&t.from_address) {
-            Some(balance) => {
-                if *balance < t.amount + TRANSACTION_COST {
-                    return Err(TransactionError::InsufficientBalance {
+    fn is_active(&self, block: &Block) -> Result<bool, LedgerError> {
+        Ok(self.active_hash(block.index())? == Some(*block.hash()))
+    }
+
+    fn active_hash(&self, index: u64) -> Result<Option<BlockHash>, LedgerError> {
+        if index < self.base || index > self.height {
+            return Ok(None);
+        }
+
+        Ok(self.storage.active_hash(index)?)
+    }
+
+    /// Stores the cumulative work of the branch ending at `block` and returns
+    /// it. The parent must already be stored, except for the first block.
+    fn insert_work(&mut self, block: &Block) -> Result<u128, LedgerError> {
+        let parent = self.storage.work(block.previous_hash())?.unwrap_or(0);
+        let work = parent.saturating_add(block.work());
+        self.storage.put_work(block.hash(), work)?;
+
+        Ok(work)
+    }
+
+    /// Appends an already applied block to the active chain, storing its undo
+    /// record.
+    fn push_block(&mut self, block: &Block, undo: StateUndo) -> Result<(), LedgerError> {
+        self.storage.put_undo(block.hash(), undo)?;
+        self.index_block(block)?;
+
+        debug_assert!(
+            self.total_supply() <= self.supply_cap(),
+            "supply exceeds the emission schedule"
+        );
+
+        Ok(())
+    }
+
+    /// Appends `block` to the active chain and indexes its transactions.
+    fn index_block(&mut self, block: &Block) -> Result<(), LedgerError> {
+        for (position, t) in block.transactions().iter().enumerate() {
+            self.storage
+                .put_transaction(&t.id(), block.index(), position)?;
+        }
+
+        self.storage.put_active_hash(block.index(), block.hash())?;
+        self.height = block.index();
+
+        Ok(())
+    }
+
+    /// Pops blocks off the active chain until `index` is the tip, reverting
+    /// their state changes. Returns the detached blocks in chain order.
+    fn rollback_to(&mut self, index: u64) -> Result<Vec<Block>, LedgerError> {
+        let mut detached = Vec::new();
+
+        while self.height > index {
+            let Some(hash) = self.active_hash(self.height)? else {
+                break;
+            };
+            let block = self.stored_block(&hash)?;
+            let undo = self
+                .storage
+                .undo(&hash)?
+                .ok_or(LedgerError::BlockNotFound(block.index()))?;
+
+            self.revert(undo)?;
+            self.storage.remove_undo(&hash)?;
+            self.storage.remove_active_hash(self.height)?;
+            for t in block.transactions() {
+                self.storage.remove_transaction(&t.id())?;
+            }
+            self.height -= 1;
+            detached.push(block);
+        }
+
+        detached.reverse();
+        Ok(detached)
+    }
+
+    fn revert(&mut self, undo: StateUndo) -> Result<(), LedgerError> {
+        for (address, account) in undo {
+            self.set_account(address, account)?;
+        }
+
+        Ok(())
+    }
+
+    /// Undo record of the block at height `height` of the active chain.
+    fn active_undo(&self, height: u64) -> Result<StateUndo, LedgerError> {
+        self.active_hash(height)?
+            .map(|hash| self.storage.undo(&hash))
+            .transpose()?
+            .flatten()
+            .ok_or(LedgerError::BlockNotFound(height))
+    }
+
+    /// State tree over the stored accounts.
+    fn state_tree(
+        &self,
+    ) -> StateTree<impl Fn(&NodeKey) -> Result<Option<StateNode>, StorageError> + '_> {
+        StateTree::new(|key: &NodeKey| self.storage.node(key))
+    }
+
+    /// Stores `account` as the record of `address`, or removes it if `None`,
+    /// updating the state tree along its path and the total supply.
+    fn set_account(
+        &mut self,
+        address: Address,
+        account: Option<Account>,
+    ) -> Result<(), LedgerError> {
+        let previous = self.storage.account(&address)?;
+        let mut tree = self.state_tree();
+        match &account {
+            Some(account) => tree.insert(account)?,
+            None => tree.remove(&address)?,
+        }
+
+        for (key, node) in tree.into_changes() {
+            match node {
+                Some(node) => self.storage.put_node(key, node)?,
+                None => self.storage.remove_node(&key)?,
+            }
+        }
+        self.supply = self.supply + account.map_or(0, |a| a.balance as u128)
+            - previous.map_or(0, |a| a.balance as u128);
+        match account {
+            Some(account) => self.storage.put_account(account)?,
+            None => self.storage.remove_account(&address)?,
+        }
+
+        Ok(())
+    }
+
+    /// Applies every transaction of `block` to the state, checks the state
+    /// root in its header and returns the undo record for it. On error, state
+    /// is left as it was before the block.
+    ///
+    /// In debug builds, also checks that the block changed the total supply by
+    /// exactly what it minted minus the fees it burnt or paid to its coinbase.
+    /// Only the accounts in the undo record can have changed, so comparing
+    /// their balances before and after is enough.
+    fn apply_transactions(&mut self, block: &Block) -> Result<StateUndo, LedgerError> {
+        let mut undo = StateUndo::new();
+
+        for (position, t) in block.transactions().iter().enumerate() {
+            for address in [t.from_address, t.to_address] {
+                if !undo.iter().any(|(a, _)| *a == address) {
+                    undo.push((address, self.storage.account(&address)?));
+                }
+            }
+
+            if let Err(e) = self.apply_transaction(block, position, t) {
+                self.revert(undo)?;
+                return Err(e);
+            }
+        }
+
+        let state_root = self.state_root()?;
+        if *block.state_root() != state_root {
+            self.revert(undo)?;
+            return Err(BlockError::InvalidStateRoot {
+                got: *block.state_root(),
+                want: state_root,
+            }
+            .into());
+        }
+
+        #[cfg(debug_assertions)]
+        {
+            let mut before = 0u128;
+            let mut after = 0u128;
+            for (address, account) in &undo {
+                before += account.map_or(0, |a| a.balance as u128);
+                after += self.balance(*address)? as u128;
+            }
+
+            let (minted, fees) = block.transactions().iter().fold((0, 0), |(m, f), t| {
+                let minted = if t.tx_type == TransactionType::Mint {
+                    t.amount
+                } else {
+                    0
+                };
+                (m + minted as u128, f + t.fee as u128)
+            });
+            debug_assert_eq!(
+                after,
+                before + minted - fees,
+                "block {} changed the supply by more than its mints minus its fees",
+                block.index()
+            );
+        }
+
+        Ok(undo)
+    }
+
+    fn apply_transaction(
+        &mut self,
+        block: &Block,
+        position: usize,
+        t: &Transaction,
+    ) -> Result<(), LedgerError> {
+        if t.tx_type == TransactionType::Mint {
+            if position != 0 {
+                return Err(LedgerError::ForbiddenMintTransaction(t.id()));
+            }
+
+            self.verify_coinbase(block, t)?;
+            return self.apply_transaction_unchecked(t);
+        }
+
+        // A block is refused as a whole if one of its transactions is invalid.
+        // Producers filter their candidates with `select_transactions` first.
+        let sender = self.account(t.from_address)?;
+        self.verify_transaction(t, &sender)?;
+        self.dry_run_transaction(t, &sender)?;
+        self.apply_transaction_unchecked(t)
+    }
+
+    /// Checks that the coinbase `t` of `block` is well formed and pays exactly
+    /// the block reward.
+    fn verify_coinbase(&self, block: &Block, t: &Transaction) -> Result<(), LedgerError> {
+        if t.nonce != block.index()
+            || t.from_address != Address::from([0u8; 32])
+            || t.from_public_key.is_some()
+            || t.fee != 0
+        {
+            return Err(LedgerError::InvalidCoinbase(t.id()));
+        }
+
+        let want = self.block_reward(block.index(), &block.transactions()[1..])?;
+        if t.amount != want {
+            return Err(LedgerError::InvalidCoinbaseAmount {
+                got: t.amount,
+                want,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the sender owns `from_address` and signed the transaction.
+    ///
+    /// Transactions may omit the public key once `sender`, the account at
+    /// `from_address`, has revealed it on chain.
+    pub(super) fn verify_transaction(
+        &self,
+        t: &Transaction,
+        sender: &Account,
+    ) -> Result<(), TransactionError> {
+        let public_key = match t.from_public_key {
+            Some(public_key) => {
+                if Address::from(public_key) != t.from_address {
+                    return Err(TransactionError::AddressKeyMismatch {
                         address: t.from_address,
-                        transaction: t.clone(),
+                        transaction: t.id(),
                     });
-                } else {
-                    return Ok(());
                 }
+                public_key
             }
-            None => Err(TransactionError::InsufficientBalance {
+            None => *sender
+                .public_key()
+                .ok_or(TransactionError::MissingPublicKey {
+                    address: t.from_address,
+                    transaction: t.id(),
+                })?,
+        };
+
+        transaction::verify_signature(t, &public_key, self.chain_id())
+    }
+
+    /// Checks the nonce, fee and funds of `t` against `sender`, the account at
+    /// `from_address`.
+    pub(super) fn dry_run_transaction(
+        &self,
+        t: &Transaction,
+        sender: &Account,
+    ) -> Result<(), TransactionError> {
+        let expected = sender.nonce();
+        if t.nonce != expected {
+            return Err(TransactionError::InvalidNonce {
+                expected,
+                got: t.nonce,
+                transaction: t.id(),
+            });
+        }
+
+        if t.fee < self.params.min_fee {
+            return Err(TransactionError::FeeTooLow {
+                minimum: self.params.min_fee,
+                got: t.fee,
+            });
+        }
+
+        let debit = t
+            .amount
+            .checked_add(t.fee)
+            .ok_or(TransactionError::Overflow(t.id()))?;
+        if sender.balance() < debit {
+            return Err(TransactionError::InsufficientBalance {
                 address: t.from_address,
-                transaction: t.clone(),
-            }),
+                transaction: t.id(),
+            });
         }
+
+        Ok(())
     }
 
+    /// Applies `t` to the two accounts it touches, writing them back only if
+    /// it succeeds.
     fn apply_transaction_unchecked(&mut self, t: &Transaction) -> Result<(), LedgerError> {
-        if t.tx_type != TransactionType::Mint {
-            let from_balance =
-                self.state
-                    .get(&t.from_address)
-                    .ok_or(LedgerError::TransactionError(
-                        TransactionError::InsufficientBalance {
-                            address: t.from_address,
-                            transaction: t.clone(),
-                        },
-                    ))?;
-
-            self.state.insert(
-                t.from_address,
-                *from_balance - (t.amount + TRANSACTION_COST),
-            );
+        let mut touched = HashMap::new();
+        for address in [t.from_address, t.to_address] {
+            if let Some(account) = self.storage.account(&address)? {
+                touched.insert(address, account);
+            }
         }
 
-        match self.state.get(&t.to_address) {
-            Some(to_balance) => {
-                self.state.insert(t.to_address, to_balance + t.amount);
-            }
-            None => {
-                self.state.insert(t.to_address, t.amount);
-            }
+        apply_to_state(&mut touched, t)?;
+        for (address, account) in touched {
+            self.set_account(address, Some(account))?;
         }
 
         Ok(())
     }
 
-    pub fn encode(&self) -> Result<Vec<u8>, LedgerError> {
-        let config = config::standard();
-        bincode::encode_to_vec(&self, config).map_err(LedgerError::from)
+    /// Hash of the tip of the active chain.
+    fn tip(&self) -> Result<BlockHash, LedgerError> {
+        self.active_hash(self.height)?
+            .ok_or(LedgerError::BlockNotFound(self.height))
     }
 
-    pub fn last(&self) -> Result<&Block, LedgerError> {
-        self.chain.last().ok_or(LedgerError::BlockNotFound(0))
+    pub fn last(&self) -> Result<Block, LedgerError> {
+        self.stored_block(&self.tip()?)
+    }
+
+    fn stored_block(&self, hash: &BlockHash) -> Result<Block, LedgerError> {
+        self.storage
+            .block(hash)?
+            .ok_or(LedgerError::UnknownParent(*hash))
     }
 }
 
-impl std::fmt::Display for Ledger {
+impl<S: Storage> std::fmt::Display for Ledger<S> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        writeln!(f, "Blockchain - Total Blocks: {}", self.chain.len())?;
-        for (i, block) in self.chain.iter().enumerate() {
-            writeln!(f, "\n=== Block {} ===\n{}", i, block)?;
+        let blocks = self.height - self.base + 1;
+        writeln!(f, "Blockchain - Total Blocks: {}", blocks)?;
+        for height in self.base..=self.height {
+            let block = self
+                .block_at(height)
+                .ok()
+                .flatten()
+                .ok_or(std::fmt::Error)?;
+            writeln!(f, "\n=== Block {} ===\n{}", height, block)?;
         }
         Ok(())
     }
 }
+
+/// Moves the funds of `t` between accounts of `state`, which must already hold
+/// the sender unless `t` is a mint.
+pub(super) fn apply_to_state(
+    state: &mut HashMap<Address, Account>,
+    t: &Transaction,
+) -> Result<(), LedgerError> {
+    if t.tx_type != TransactionType::Mint {
+        let from = state
+            .get_mut(&t.from_address)
+            .ok_or(LedgerError::TransactionError(
+                TransactionError::InsufficientBalance {
+                    address: t.from_address,
+                    transaction: t.id(),
+                },
+            ))?;
+
+        let debit = t
+            .amount
+            .checked_add(t.fee)
+            .ok_or(TransactionError::Overflow(t.id()))?;
+        from.balance =
+            from.balance
+                .checked_sub(debit)
+                .ok_or(TransactionError::InsufficientBalance {
+                    address: t.from_address,
+                    transaction: t.id(),
+                })?;
+        from.nonce = from
+            .nonce
+            .checked_add(1)
+            .ok_or(TransactionError::Overflow(t.id()))?;
+        if from.pkey.is_none() {
+            from.pkey = t.from_public_key;
+        }
+    }
+
+    let to = state
+        .entry(t.to_address)
+        .or_insert_with(|| Account::new(t.to_address));
+    to.balance = to
+        .balance
+        .checked_add(t.amount)
+        .ok_or(TransactionError::Overflow(t.id()))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::OnceLock;
+
+    use proptest::prelude::*;
+
+    use crate::account::state_root;
+    use crate::genesis::INITIAL_DIFFICULTY;
+
+    use super::*;
+    use crate::ledger::testing::{self, Key};
+
+    /// Funded account `a`, and a ledger whose genesis pays it.
+    fn funded() -> (Ledger, Key) {
+        let a = Key::new();
+        let ledger = Ledger::from_genesis(
+            testing::genesis(&[(a.address, 1_000)]),
+            ConsensusParams::default(),
+        )
+        .unwrap();
+        (ledger, a)
+    }
+
+    fn assert_rejected<F>(ledger: &mut Ledger, t: Transaction, matches: F)
+    where
+        F: Fn(&LedgerError) -> bool,
+    {
+        let height = ledger.height();
+        let root = ledger.state_root().unwrap();
+        let sender = ledger.account(t.from_address).unwrap();
+
+        let report = ledger.select_transactions(vec![t]);
+        assert!(report.accepted.is_empty());
+        assert!(
+            matches(&report.rejected[0].reason),
+            "{:?}",
+            report.rejected[0].reason
+        );
+
+        let producer = Address::from([9u8; 32]);
+        let block = testing::forge(ledger, producer, vec![t]);
+        let e = ledger.append_block(block).unwrap_err();
+        assert!(matches(&e), "{e:?}");
+
+        assert_eq!(ledger.height(), height);
+        assert_eq!(ledger.state_root().unwrap(), root);
+        assert_eq!(ledger.account(t.from_address).unwrap(), sender);
+        assert_eq!(ledger.balance(producer).unwrap(), 0);
+    }
+
+    #[test]
+    fn spend_signed_by_foreign_key_is_rejected() {
+        let (mut ledger, a) = funded();
+        let b = Key::new();
+
+        let mut t = Transaction::transfer(b.public_key, b.address, 100, 1, 0);
+        t.from_address = a.address;
+        let t = b.sign(t);
+
+        assert_rejected(&mut ledger, t, |e| {
+            matches!(
+                e,
+                LedgerError::TransactionError(TransactionError::AddressKeyMismatch { address, .. })
+                    if *address == a.address
+            )
+        });
+    }
+
+    #[test]
+    fn spend_without_revealed_key_is_rejected() {
+        let (mut ledger, a) = funded();
+        let t =
+            a.sign(Transaction::transfer(a.public_key, a.address, 100, 1, 0).without_public_key());
+
+        assert_rejected(&mut ledger, t, |e| {
+            matches!(
+                e,
+                LedgerError::TransactionError(TransactionError::MissingPublicKey { address, .. })
+                    if *address == a.address
+            )
+        });
+    }
+
+    #[test]
+    fn spend_from_revealed_account_needs_its_key() {
+        let (mut ledger, a) = funded();
+        let b = Key::new();
+        let reveal = a.transfer(b.address, 100, 1, 0);
+        testing::mine(&mut ledger, b.address, vec![reveal]).unwrap();
+        assert_eq!(
+            ledger.account(a.address).unwrap().public_key(),
+            Some(&a.public_key)
+        );
+
+        // The key may now be omitted, but the signature must still be a's.
+        let mut forged =
+            Transaction::transfer(b.public_key, b.address, 100, 1, 1).without_public_key();
+        forged.from_address = a.address;
+        let forged = b.sign(forged);
+        assert_rejected(&mut ledger, forged, |e| {
+            matches!(
+                e,
+                LedgerError::TransactionError(TransactionError::VerificationError { .. })
+            )
+        });
+
+        let t =
+            a.sign(Transaction::transfer(a.public_key, b.address, 100, 1, 1).without_public_key());
+        testing::mine(&mut ledger, b.address, vec![t]).unwrap();
+        assert_eq!(ledger.balance(a.address).unwrap(), 1_000 - 2 * 101);
+    }
+
+    #[test]
+    fn replayed_transfer_is_rejected() {
+        let (mut ledger, a) = funded();
+        let t = a.transfer(Address::from([7u8; 32]), 100, 1, 0);
+        testing::mine(&mut ledger, Address::from([8u8; 32]), vec![t]).unwrap();
+
+        assert_rejected(&mut ledger, t, |e| {
+            matches!(
+                e,
+                LedgerError::TransactionError(TransactionError::InvalidNonce {
+                    expected: 1,
+                    got: 0,
+                    transaction,
+                }) if *transaction == t.id()
+            )
+        });
+        assert_eq!(ledger.balance(Address::from([7u8; 32])).unwrap(), 100);
+    }
+
+    #[test]
+    fn account_proofs_match_every_block_of_the_chain() {
+        let (mut ledger, a) = funded();
+        let b = Key::new();
+        for nonce in 0..3 {
+            let t = a.transfer(b.address, 10, 1, nonce);
+            testing::mine(&mut ledger, Address::from([9u8; 32]), vec![t]).unwrap();
+        }
+
+        for index in 0..=ledger.height() {
+            let root = *ledger.block_at(index).unwrap().unwrap().state_root();
+            assert_eq!(state_root(&ledger.accounts_at(index).unwrap()), root);
+            for address in [a.address, b.address] {
+                let (account, proof) = ledger.account_proof(address, index).unwrap();
+                assert!(proof.verify(&address, account.as_ref(), &root));
+            }
+        }
+
+        let (account, _) = ledger.account_proof(b.address, 1).unwrap();
+        assert_eq!(account.map(|b| b.balance()), Some(10));
+        assert_eq!(ledger.account_proof(b.address, 0).unwrap().0, None);
+        assert!(matches!(
+            ledger.account_proof(b.address, 4),
+            Err(LedgerError::BlockNotFound(4))
+        ));
+    }
+
+    #[test]
+    fn reorganisation_rolls_the_state_tree_back() {
+        let (mut ledger, a) = funded();
+        let mut fork = ledger.clone();
+        let t = a.transfer(Address::from([7u8; 32]), 10, 1, 0);
+        testing::mine(&mut ledger, Address::from([8u8; 32]), vec![t]).unwrap();
+
+        let mut branch = Vec::new();
+        for nonce in 0..2 {
+            let t = a.transfer(Address::from([6u8; 32]), 20, 1, nonce);
+            branch.push(testing::mine(&mut fork, Address::from([9u8; 32]), vec![t]).unwrap());
+        }
+        for block in branch {
+            ledger.append_block(block).unwrap();
+        }
+
+        assert_eq!(ledger.last().unwrap(), fork.last().unwrap());
+        assert_eq!(ledger.state_root().unwrap(), fork.state_root().unwrap());
+        assert_eq!(ledger.total_supply(), fork.total_supply());
+        assert_eq!(ledger.balance(Address::from([7u8; 32])).unwrap(), 0);
+        assert_eq!(ledger.balance(a.address).unwrap(), 1_000 - 2 * 21);
+    }
+
+    fn now() -> u128 {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis()
+    }
+
+    /// Block with just a coinbase, built on `previous_hash` with the given
+    /// header fields and otherwise valid on top of the tip of `ledger`.
+    fn forge_header(
+        ledger: &Ledger,
+        index: u64,
+        timestamp: u128,
+        previous_hash: BlockHash,
+    ) -> Block {
+        let transactions = vec![ledger.coinbase(Address::from([9u8; 32]), &[]).unwrap()];
+        Block::forge(
+            index,
+            timestamp,
+            previous_hash,
+            transactions.clone(),
+            ledger.state_root_after(&transactions).unwrap(),
+            ledger.last().unwrap().difficulty(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn invalid_headers_leave_the_ledger_untouched() {
+        let (mut ledger, a) = funded();
+        let t = a.transfer(Address::from([7u8; 32]), 10, 1, 0);
+        testing::mine(&mut ledger, Address::from([9u8; 32]), vec![t]).unwrap();
+
+        let tip = ledger.last().unwrap();
+        let root = ledger.state_root().unwrap();
+        let supply = ledger.total_supply();
+        let balance = ledger.balance(a.address).unwrap();
+        let (index, timestamp, hash) = (tip.index() + 1, tip.timestamp(), *tip.hash());
+        let later = now().max(timestamp + 1);
+
+        type Check = fn(&LedgerError) -> bool;
+        let cases: [(Block, Check); 7] = [
+            (forge_header(&ledger, index + 1, later, hash), |e| {
+                matches!(
+                    e,
+                    LedgerError::BlockError(BlockError::InvalidIndex { got: 3, want: 2 })
+                )
+            }),
+            (forge_header(&ledger, index - 1, later, hash), |e| {
+                matches!(
+                    e,
+                    LedgerError::BlockError(BlockError::InvalidIndex { got: 1, want: 2 })
+                )
+            }),
+            (forge_header(&ledger, index, timestamp, hash), |e| {
+                matches!(
+                    e,
+                    LedgerError::BlockError(BlockError::InvalidTimestamp { .. })
+                )
+            }),
+            (forge_header(&ledger, index, timestamp - 1, hash), |e| {
+                matches!(
+                    e,
+                    LedgerError::BlockError(BlockError::InvalidTimestamp { .. })
+                )
+            }),
+            (
+                forge_header(&ledger, index, later + MAX_FUTURE_DRIFT_MS + 60_000, hash),
+                |e| {
+                    matches!(
+                        e,
+                        LedgerError::BlockError(BlockError::InvalidTimestamp { .. })
+                    )
+                },
+            ),
+            (
+                forge_header(&ledger, index, later, BlockHash::from([5u8; 32])),
+                |e| matches!(e, LedgerError::UnknownParent(hash) if *hash == BlockHash::from([5u8; 32])),
+            ),
+            (tip.clone(), |e| matches!(e, LedgerError::DuplicateBlock(_))),
+        ];
+
+        for (i, (block, matches)) in cases.into_iter().enumerate() {
+            let e = ledger.append_block(block.clone()).unwrap_err();
+            assert!(matches(&e), "case {i}: {e:?}");
+
+            assert_eq!(ledger.last().unwrap(), tip, "case {i}");
+            assert_eq!(ledger.state_root().unwrap(), root, "case {i}");
+            assert_eq!(ledger.total_supply(), supply, "case {i}");
+            assert_eq!(ledger.balance(a.address).unwrap(), balance, "case {i}");
+            if block != tip {
+                assert_eq!(ledger.block(block.hash()).unwrap(), None, "case {i}");
+            }
+        }
+
+        // Within the allowed drift, a block from the future is fine.
+        let block = forge_header(&ledger, index, later + MAX_FUTURE_DRIFT_MS / 2, hash);
+        ledger.append_block(block).unwrap();
+        assert_eq!(ledger.height(), index);
+    }
+
+    #[test]
+    fn shorter_branch_with_more_work_wins() {
+        let params = ConsensusParams {
+            retarget_interval: 3,
+            target_block_time_ms: 10,
+            ..ConsensusParams::default()
+        };
+        let mut ledger = Ledger::from_genesis(testing::genesis(&[]), params).unwrap();
+        let producer = Address::from([9u8; 32]);
+        let start = now();
+        for i in 1..=2 {
+            let block = testing::forge_at(&ledger, producer, vec![], start + 10 * i);
+            ledger.append_block(block).unwrap();
+        }
+
+        // Blocks 3 to 5 of the fork come in far faster than targeted, so
+        // block 6 is two difficulty steps harder than anything on the longer
+        // chain, which keeps to the target.
+        let mut fork = ledger.clone();
+        for i in 3..=8 {
+            let block = testing::forge_at(&ledger, producer, vec![], start + 10 * i);
+            ledger.append_block(block).unwrap();
+        }
+        let mut branch = Vec::new();
+        for i in 3..=6 {
+            let block = testing::forge_at(&fork, producer, vec![], start + 20 + i);
+            fork.append_block(block.clone()).unwrap();
+            branch.push(block);
+        }
+        assert_eq!(ledger.last().unwrap().difficulty(), INITIAL_DIFFICULTY);
+        assert_eq!(fork.last().unwrap().difficulty(), INITIAL_DIFFICULTY + 2);
+
+        let tip = ledger.last().unwrap();
+        let (heavy, rest) = branch.split_last().unwrap();
+        for block in rest {
+            ledger.append_block(block.clone()).unwrap();
+            assert_eq!(ledger.last().unwrap(), tip);
+        }
+        ledger.append_block(heavy.clone()).unwrap();
+
+        assert_eq!(ledger.height(), 6);
+        assert_eq!(ledger.last().unwrap(), *heavy);
+        assert_eq!(ledger.work().unwrap(), fork.work().unwrap());
+        assert!(ledger.block(tip.hash()).unwrap().is_some());
+    }
+
+    #[test]
+    fn difficulty_follows_the_retarget_schedule() {
+        let params = ConsensusParams {
+            retarget_interval: 3,
+            target_block_time_ms: 10,
+            ..ConsensusParams::default()
+        };
+        let mut ledger = Ledger::from_genesis(testing::genesis(&[]), params).unwrap();
+        let producer = Address::from([9u8; 32]);
+        let start = now();
+
+        // The first window would span the genesis timestamp and is skipped,
+        // however long it appears to have taken.
+        for i in 1..=5 {
+            let block = testing::forge_at(&ledger, producer, vec![], start + i);
+            assert_eq!(block.difficulty(), INITIAL_DIFFICULTY);
+            ledger.append_block(block).unwrap();
+        }
+
+        let parent = ledger.last().unwrap();
+        assert_eq!(
+            ledger.next_difficulty(&parent).unwrap(),
+            INITIAL_DIFFICULTY + 2
+        );
+        for difficulty in [INITIAL_DIFFICULTY, INITIAL_DIFFICULTY + 1] {
+            let block = Block::forge(
+                parent.index() + 1,
+                parent.timestamp() + 1,
+                *parent.hash(),
+                vec![ledger.coinbase(producer, &[]).unwrap()],
+                BlockHash::ZERO,
+                difficulty,
+            )
+            .unwrap();
+            assert!(matches!(
+                ledger.append_block(block),
+                Err(LedgerError::BlockError(BlockError::InvalidDifficulty { got, want }))
+                    if got == difficulty && want == INITIAL_DIFFICULTY + 2
+            ));
+        }
+        assert_eq!(ledger.height(), 5);
+
+        let block = testing::forge_at(&ledger, producer, vec![], start + 6);
+        ledger.append_block(block).unwrap();
+        assert_eq!(ledger.last().unwrap().difficulty(), INITIAL_DIFFICULTY + 2);
+    }
+
+    #[test]
+    fn invalid_block_on_heavier_branch_is_dropped() {
+        let (mut ledger, a) = funded();
+        let producer = Address::from([9u8; 32]);
+        let mut fork = ledger.clone();
+        for nonce in 0..3 {
+            let t = a.transfer(Address::from([7u8; 32]), 10, 1, nonce);
+            testing::mine(&mut ledger, producer, vec![t]).unwrap();
+        }
+        let tip = ledger.last().unwrap();
+        let root = ledger.state_root().unwrap();
+
+        // A valid block, then one committing to the wrong state and two
+        // descendants of it, together heavier than the active chain.
+        let valid = testing::mine(&mut fork, producer, vec![]).unwrap();
+        let mut branch = vec![valid.clone()];
+        let mut parent = valid.clone();
+        for _ in 0..3 {
+            let index = parent.index() + 1;
+            let coinbase = Transaction::mint(producer, fork.params.subsidy(index), index);
+            let block = Block::forge(
+                index,
+                parent.timestamp() + 1,
+                *parent.hash(),
+                vec![coinbase],
+                BlockHash::ZERO,
+                parent.difficulty(),
+            )
+            .unwrap();
+            branch.push(block.clone());
+            parent = block;
+        }
+
+        let (heavy, rest) = branch.split_last().unwrap();
+        for block in rest {
+            ledger.append_block(block.clone()).unwrap();
+        }
+        let e = ledger.append_block(heavy.clone()).unwrap_err();
+        assert!(
+            matches!(
+                e,
+                LedgerError::BlockError(BlockError::InvalidStateRoot { .. })
+            ),
+            "{e:?}"
+        );
+
+        assert_eq!(ledger.last().unwrap(), tip);
+        assert_eq!(ledger.state_root().unwrap(), root);
+        assert_eq!(ledger.nonce(a.address).unwrap(), 3);
+        assert!(ledger.block(valid.hash()).unwrap().is_some());
+        for invalid in &branch[1..] {
+            assert_eq!(ledger.block(invalid.hash()).unwrap(), None);
+        }
+        assert!(matches!(
+            ledger.append_block(branch[2].clone()),
+            Err(LedgerError::UnknownParent(hash)) if hash == *branch[1].hash()
+        ));
+    }
+
+    #[test]
+    fn detached_transactions_return_to_pending() {
+        let (mut ledger, a) = funded();
+        let producer = Address::from([9u8; 32]);
+        let mut fork = ledger.clone();
+        let kept = a.transfer(Address::from([7u8; 32]), 10, 1, 0);
+        let orphaned = a.transfer(Address::from([7u8; 32]), 10, 1, 1);
+        let mined = testing::mine(&mut ledger, producer, vec![kept, orphaned]).unwrap();
+        assert!(ledger.pending().is_empty());
+
+        testing::mine(&mut fork, producer, vec![kept]).unwrap();
+        let block = testing::mine(&mut fork, producer, vec![]).unwrap();
+        ledger
+            .append_block(fork.block_at(1).unwrap().unwrap())
+            .unwrap();
+        ledger.append_block(block).unwrap();
+
+        assert_eq!(ledger.last().unwrap(), fork.last().unwrap());
+        assert!(ledger.block(mined.hash()).unwrap().is_some());
+        assert_eq!(ledger.pending(), [orphaned]);
+        assert_eq!(ledger.nonce(a.address).unwrap(), 1);
+        assert!(
+            ledger
+                .select_transactions(ledger.pending().to_vec())
+                .rejected
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn transactions_are_found_on_the_active_chain_only() {
+        let (mut ledger, a) = funded();
+        let producer = Address::from([8u8; 32]);
+        let mut fork = ledger.clone();
+        let kept = a.transfer(Address::from([7u8; 32]), 10, 1, 0);
+        let detached = a.transfer(Address::from([7u8; 32]), 10, 1, 1);
+        testing::mine(&mut ledger, producer, vec![]).unwrap();
+        let mined = testing::mine(&mut ledger, producer, vec![kept, detached]).unwrap();
+
+        let (block, position) = ledger.find_transaction(&detached.id()).unwrap().unwrap();
+        assert_eq!((block.index(), position), (2, 2));
+        assert_eq!(block, mined);
+        assert_eq!(block.transactions()[position], detached);
+
+        testing::mine(&mut fork, producer, vec![kept]).unwrap();
+        testing::mine(&mut fork, producer, vec![]).unwrap();
+        testing::mine(&mut fork, producer, vec![]).unwrap();
+        for index in 1..=3 {
+            ledger
+                .append_block(fork.block_at(index).unwrap().unwrap())
+                .unwrap();
+        }
+
+        assert_eq!(ledger.last().unwrap(), fork.last().unwrap());
+        assert!(ledger.find_transaction(&detached.id()).unwrap().is_none());
+        let (block, position) = ledger.find_transaction(&kept.id()).unwrap().unwrap();
+        assert_eq!((block.index(), position), (1, 1));
+    }
+
+    #[test]
+    fn encoded_ledger_round_trips() {
+        let (mut ledger, a) = funded();
+        let b = Key::new();
+        testing::mine(
+            &mut ledger,
+            b.address,
+            vec![a.transfer(b.address, 100, 1, 0)],
+        )
+        .unwrap();
+
+        let bytes = ledger.encode().unwrap();
+        let decoded = Ledger::from_bytes(bytes, ledger.genesis.clone(), ledger.params).unwrap();
+        assert_eq!(decoded.height(), 1);
+        assert_eq!(decoded.state_root().unwrap(), ledger.state_root().unwrap());
+        assert_eq!(
+            decoded.balance(b.address).unwrap(),
+            ledger.balance(b.address).unwrap()
+        );
+    }
+
+    #[test]
+    fn encoded_ledger_with_forged_genesis_is_rejected() {
+        let (trusted, _) = funded();
+        let attacker = Key::new();
+        let params = ConsensusParams {
+            initial_subsidy: u64::MAX / 2,
+            ..trusted.params
+        };
+        let forged =
+            Ledger::from_genesis(testing::genesis(&[(attacker.address, u64::MAX)]), params)
+                .unwrap();
+        let bytes = forged.encode().unwrap();
+
+        let e = Ledger::from_bytes(bytes, trusted.genesis.clone(), trusted.params).unwrap_err();
+        assert!(matches!(e, LedgerError::GenesisConfigMismatch), "{e:?}");
+    }
+
+    #[test]
+    fn encoded_ledger_with_other_params_is_rejected() {
+        let (trusted, _) = funded();
+        let mut forged = trusted.clone();
+        forged.params.initial_subsidy *= 2;
+        let bytes = forged.encode().unwrap();
+
+        let e = Ledger::from_bytes(bytes, trusted.genesis.clone(), trusted.params).unwrap_err();
+        assert!(matches!(e, LedgerError::ParamsMismatch), "{e:?}");
+    }
+
+    #[test]
+    fn encoded_chain_must_start_at_trusted_genesis() {
+        let (trusted, _) = funded();
+        let attacker = Key::new();
+        let mut forged = Ledger::from_genesis(
+            testing::genesis(&[(attacker.address, 1_000)]),
+            trusted.params,
+        )
+        .unwrap();
+        forged.genesis = trusted.genesis.clone();
+        let bytes = forged.encode().unwrap();
+
+        let e = Ledger::from_bytes(bytes, trusted.genesis.clone(), trusted.params).unwrap_err();
+        assert!(matches!(e, LedgerError::GenesisBlockError(_)), "{e:?}");
+    }
+
+    #[test]
+    fn encoded_ledger_with_tampered_state_is_rejected() {
+        let (mut ledger, a) = funded();
+        let mut account = ledger.account(a.address).unwrap();
+        account.balance += 1;
+        ledger.storage.put_account(account).unwrap();
+        let bytes = ledger.encode().unwrap();
+
+        let e = Ledger::from_bytes(bytes, ledger.genesis.clone(), ledger.params).unwrap_err();
+        assert!(matches!(e, LedgerError::StateMismatch), "{e:?}");
+    }
+
+    fn assert_coinbase_rejected<F>(ledger: &mut Ledger, transactions: Vec<Transaction>, matches: F)
+    where
+        F: Fn(&LedgerError) -> bool,
+    {
+        let height = ledger.height();
+        let root = ledger.state_root().unwrap();
+
+        let block = ledger.forge(transactions).unwrap();
+        let e = ledger.append_block(block).unwrap_err();
+        assert!(matches(&e), "{e:?}");
+        assert_eq!(ledger.height(), height);
+        assert_eq!(ledger.state_root().unwrap(), root);
+    }
+
+    #[test]
+    fn coinbase_must_pay_exactly_the_block_reward() {
+        let (mut ledger, a) = funded();
+        let producer = Address::from([9u8; 32]);
+        let t = a.transfer(producer, 100, 5, 0);
+        let want = ledger.params.subsidy(1) + 5;
+
+        for amount in [want + 1, want - 1, want - 5] {
+            assert_coinbase_rejected(
+                &mut ledger,
+                vec![Transaction::mint(producer, amount, 1), t],
+                |e| {
+                    matches!(e, LedgerError::InvalidCoinbaseAmount { got, want: w }
+                        if *got == amount && *w == want)
+                },
+            );
+        }
+
+        let block = ledger
+            .forge(vec![Transaction::mint(producer, want, 1), t])
+            .unwrap();
+        ledger.append_block(block).unwrap();
+        assert_eq!(ledger.balance(producer).unwrap(), want + 100);
+        assert_eq!(
+            ledger.total_supply(),
+            1_000 + ledger.params.subsidy(1) as u128
+        );
+    }
+
+    #[test]
+    fn malformed_coinbase_is_rejected() {
+        let (mut ledger, a) = funded();
+        let producer = Address::from([9u8; 32]);
+        let reward = ledger.params.subsidy(1);
+
+        assert_coinbase_rejected(
+            &mut ledger,
+            vec![Transaction::mint(producer, reward, 2)],
+            |e| matches!(e, LedgerError::InvalidCoinbase(_)),
+        );
+
+        let mut fee = Transaction::mint(producer, reward, 1);
+        fee.fee = 1;
+        assert_coinbase_rejected(&mut ledger, vec![fee], |e| {
+            matches!(e, LedgerError::InvalidCoinbase(_))
+        });
+
+        let t = a.transfer(producer, 100, 1, 0);
+        assert_coinbase_rejected(
+            &mut ledger,
+            vec![t, Transaction::mint(producer, reward + 1, 1)],
+            |e| matches!(e, LedgerError::ForbiddenMintTransaction(_)),
+        );
+    }
+
+    #[test]
+    fn coinbase_on_top_of_full_allocation_is_within_cap() {
+        let a = Key::new();
+        let mut ledger = Ledger::from_genesis(
+            testing::genesis(&[(a.address, u64::MAX)]),
+            ConsensusParams::default(),
+        )
+        .unwrap();
+
+        let producer = Address::from([9u8; 32]);
+        testing::mine(&mut ledger, producer, vec![]).unwrap();
+        assert_eq!(
+            ledger.total_supply(),
+            u64::MAX as u128 + ledger.params.subsidy(1) as u128
+        );
+        assert!(ledger.total_supply() <= ledger.supply_cap());
+    }
+
+    /// Accounts the supply property test moves funds between: three keys and
+    /// an address that never sends.
+    fn accounts() -> &'static (Vec<Key>, Address) {
+        static ACCOUNTS: OnceLock<(Vec<Key>, Address)> = OnceLock::new();
+        ACCOUNTS.get_or_init(|| {
+            (
+                (0..3).map(|_| Key::new()).collect(),
+                Address::from([7u8; 32]),
+            )
+        })
+    }
+
+    fn amount() -> impl Strategy<Value = u64> {
+        prop_oneof![0..2_000u64, (u64::MAX - 2_000)..=u64::MAX, any::<u64>()]
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(24))]
+
+        /// Whatever transfers are attempted, blocks built from the accepted
+        /// ones are valid and only their subsidies change the total supply.
+        #[test]
+        fn supply_only_grows_by_subsidies(
+            allocations in (amount(), 0..2_000u64, 0..2_000u64),
+            blocks in prop::collection::vec(
+                prop::collection::vec((0..3usize, 0..4usize, amount(), 0..3u64), 0..4),
+                1..4,
+            ),
+        ) {
+            let (keys, outsider) = accounts();
+            let (big, small, smaller) = allocations;
+            let big = big.min(u64::MAX - small - smaller);
+            let genesis = testing::genesis(&[
+                (keys[0].address, big),
+                (keys[1].address, small),
+                (keys[2].address, smaller),
+            ]);
+            let allocated = big as u128 + small as u128 + smaller as u128;
+            let mut ledger = Ledger::from_genesis(genesis, ConsensusParams::default()).unwrap();
+
+            let mut minted = 0u128;
+            for transfers in blocks {
+                let mut nonces: Vec<u64> =
+                    keys.iter().map(|k| ledger.nonce(k.address).unwrap()).collect();
+                let candidates = transfers
+                    .into_iter()
+                    .map(|(from, to, amount, fee)| {
+                        let to = keys.get(to).map_or(*outsider, |k| k.address);
+                        let t = keys[from].transfer(to, amount, fee, nonces[from]);
+                        nonces[from] += 1;
+                        t
+                    })
+                    .collect();
+
+                // The coinbase is credited ahead of the transfers, so it is
+                // paid to an account they cannot push towards overflow.
+                let report = ledger.select_transactions(candidates);
+                testing::mine(&mut ledger, Address::from([8u8; 32]), report.accepted).unwrap();
+                minted += ledger.params.subsidy(ledger.height()) as u128;
+
+                let supply = ledger.total_supply();
+                let balances = ledger.storage().accounts().unwrap();
+                prop_assert_eq!(supply, balances.iter().map(|a| a.balance as u128).sum());
+                prop_assert_eq!(supply, allocated + minted);
+                prop_assert!(supply <= ledger.supply_cap());
+            }
+        }
+    }
+}
@@ -1,5 +1,13 @@
+mod builder;
 mod error;
 mod ledger;
+mod params;
+mod snapshot;
+#[cfg(test)]
+pub(crate) mod testing;
 
+pub use builder::{BlockReport, Rejection};
 pub use error::LedgerError;
 pub use ledger::Ledger;
+pub use params::ConsensusParams;
+pub use snapshot::Snapshot;
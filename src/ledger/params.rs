@@ -0,0 +1,270 @@
+use std::fs;
+use std::path::Path;
+
+use bincode::{Decode, Encode};
+use serde::{Deserialize, Deserializer};
+
+use crate::genesis::GenesisError;
+
+pub const TARGET_BLOCK_TIME_MS: u128 = 10_000;
+pub const RETARGET_INTERVAL: u64 = 10;
+pub const MIN_DIFFICULTY: u32 = 1;
+pub const INITIAL_SUBSIDY: u64 = 5_000_000_000;
+pub const HALVING_INTERVAL: u64 = 210_000;
+pub const MIN_FEE: u64 = 1;
+
+/// Consensus rules a ledger validates blocks against.
+///
+/// Loaded from the `[consensus]` table of a genesis file, where any field left
+/// out keeps its default:
+///
+/// ```toml
+/// [consensus]
+/// target_block_time_ms = 10000
+/// retarget_interval = 10
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Encode, Decode)]
+#[serde(default)]
+pub struct ConsensusParams {
+    /// Time the network aims to spend mining each block.
+    #[serde(deserialize_with = "deserialize_u64")]
+    pub target_block_time_ms: u128,
+    /// Number of blocks between two difficulty adjustments.
+    pub retarget_interval: u64,
+    /// Reward paid by the coinbase of the first blocks, before any halving.
+    pub initial_subsidy: u64,
+    /// Number of blocks between two halvings of the subsidy. Zero disables
+    /// halving.
+    pub halving_interval: u64,
+    /// Smallest fee a transfer may pay to be included in a block.
+    pub min_fee: u64,
+}
+
+impl Default for ConsensusParams {
+    fn default() -> Self {
+        Self {
+            target_block_time_ms: TARGET_BLOCK_TIME_MS,
+            retarget_interval: RETARGET_INTERVAL,
+            initial_subsidy: INITIAL_SUBSIDY,
+            halving_interval: HALVING_INTERVAL,
+            min_fee: MIN_FEE,
+        }
+    }
+}
+
+impl ConsensusParams {
+    /// Reads the `[consensus]` table of the genesis file at `path`, or the
+    /// defaults if it has none.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, GenesisError> {
+        #[derive(Deserialize)]
+        struct GenesisFile {
+            #[serde(default)]
+            consensus: ConsensusParams,
+        }
+
+        let contents = fs::read_to_string(path)?;
+        Ok(toml::from_str::<GenesisFile>(&contents)?.consensus)
+    }
+
+    /// New coins the coinbase of the block at `index` may mint, on top of the
+    /// fees collected in that block. The genesis block earns no subsidy.
+    pub fn subsidy(&self, index: u64) -> u64 {
+        if index == 0 {
+            return 0;
+        }
+        if self.halving_interval == 0 {
+            return self.initial_subsidy;
+        }
+
+        let halvings = index / self.halving_interval;
+        self.initial_subsidy
+            .checked_shr(halvings as u32)
+            .unwrap_or(0)
+    }
+
+    /// Total subsidy paid over the life of the chain, or `u128::MAX` if it
+    /// never stops.
+    pub fn max_emission(&self) -> u128 {
+        if self.halving_interval == 0 {
+            return if self.initial_subsidy == 0 {
+                0
+            } else {
+                u128::MAX
+            };
+        }
+
+        let mut total: u128 = 0;
+        let mut era_start = 1;
+        loop {
+            let subsidy = self.subsidy(era_start);
+            if subsidy == 0 {
+                return total;
+            }
+
+            let era_end = (era_start / self.halving_interval + 1) * self.halving_interval;
+            let blocks = era_end - era_start;
+            total = total.saturating_add(subsidy as u128 * blocks as u128);
+            era_start = era_end;
+        }
+    }
+
+    /// Difficulty following a retarget window that should have taken
+    /// `expected_ms` but took `actual_ms`.
+    ///
+    /// Difficulty is counted in leading zero bits, so each step doubles or
+    /// halves the work per block. It moves by one step when blocks came more
+    /// than twice as fast or slow as targeted, and by two steps past four times.
+    pub fn retarget(&self, difficulty: u32, actual_ms: u128, expected_ms: u128) -> u32 {
+        let actual_ms = actual_ms.max(1);
+
+        let adjusted = if actual_ms.saturating_mul(4) < expected_ms {
+            difficulty.saturating_add(2)
+        } else if actual_ms.saturating_mul(2) < expected_ms {
+            difficulty.saturating_add(1)
+        } else if actual_ms > expected_ms.saturating_mul(4) {
+            difficulty.saturating_sub(2)
+        } else if actual_ms > expected_ms.saturating_mul(2) {
+            difficulty.saturating_sub(1)
+        } else {
+            difficulty
+        };
+
+        adjusted.clamp(MIN_DIFFICULTY, 255)
+    }
+}
+
+/// Reads a `u128` written as a TOML integer, which is at most 64 bits wide.
+fn deserialize_u64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u128, D::Error> {
+    u64::deserialize(deserializer).map(u128::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(initial_subsidy: u64, halving_interval: u64) -> ConsensusParams {
+        ConsensusParams {
+            initial_subsidy,
+            halving_interval,
+            ..ConsensusParams::default()
+        }
+    }
+
+    #[test]
+    fn subsidy_halves_at_interval_boundaries() {
+        let p = ConsensusParams::default();
+        let h = HALVING_INTERVAL;
+
+        assert_eq!(p.subsidy(0), 0);
+        assert_eq!(p.subsidy(1), INITIAL_SUBSIDY);
+        assert_eq!(p.subsidy(h - 1), INITIAL_SUBSIDY);
+        assert_eq!(p.subsidy(h), INITIAL_SUBSIDY / 2);
+        assert_eq!(p.subsidy(2 * h - 1), INITIAL_SUBSIDY / 2);
+        assert_eq!(p.subsidy(2 * h), INITIAL_SUBSIDY / 4);
+        assert_eq!(p.subsidy(64 * h), 0);
+        assert_eq!(p.subsidy(u64::MAX), 0);
+    }
+
+    #[test]
+    fn subsidy_without_halving_is_constant() {
+        let p = params(50, 0);
+
+        assert_eq!(p.subsidy(0), 0);
+        assert_eq!(p.subsidy(1), 50);
+        assert_eq!(p.subsidy(u64::MAX), 50);
+        assert_eq!(p.max_emission(), u128::MAX);
+        assert_eq!(params(0, 0).max_emission(), 0);
+    }
+
+    #[test]
+    fn max_emission_is_the_sum_of_every_subsidy() {
+        for (initial, interval) in [(1_000, 10), (1, 1), (7, 3), (u64::MAX, 1), (1 << 40, 5)] {
+            let p = params(initial, interval);
+
+            let mut total: u128 = 0;
+            let mut index = 1;
+            while p.subsidy(index) > 0 {
+                total += p.subsidy(index) as u128;
+                index += 1;
+            }
+
+            assert_eq!(
+                p.max_emission(),
+                total,
+                "initial {initial}, interval {interval}"
+            );
+        }
+    }
+
+    #[test]
+    fn default_emission_stays_below_twice_the_first_era() {
+        let p = ConsensusParams::default();
+        let first_era = INITIAL_SUBSIDY as u128 * (HALVING_INTERVAL - 1) as u128;
+
+        assert!(p.max_emission() > first_era);
+        assert!(p.max_emission() < 2 * INITIAL_SUBSIDY as u128 * HALVING_INTERVAL as u128);
+    }
+
+    #[test]
+    fn retarget_steps_at_twice_and_four_times_the_target() {
+        let p = ConsensusParams::default();
+        let expected = 1_000;
+
+        for (actual, difficulty) in [
+            (0, 12),
+            (249, 12),
+            (250, 11),
+            (499, 11),
+            (500, 10),
+            (1_000, 10),
+            (2_000, 10),
+            (2_001, 9),
+            (4_000, 9),
+            (4_001, 8),
+            (u128::MAX, 8),
+        ] {
+            assert_eq!(
+                p.retarget(10, actual, expected),
+                difficulty,
+                "actual {actual}"
+            );
+        }
+    }
+
+    #[test]
+    fn retarget_is_clamped() {
+        let p = ConsensusParams::default();
+
+        assert_eq!(p.retarget(MIN_DIFFICULTY, 10_000, 1_000), MIN_DIFFICULTY);
+        assert_eq!(
+            p.retarget(MIN_DIFFICULTY + 1, 10_000, 1_000),
+            MIN_DIFFICULTY
+        );
+        assert_eq!(p.retarget(0, 1_000, 1_000), MIN_DIFFICULTY);
+        assert_eq!(p.retarget(254, 1, 1_000), 255);
+        assert_eq!(p.retarget(255, 1, 1_000), 255);
+        assert_eq!(p.retarget(u32::MAX, 1, 1_000), 255);
+    }
+
+    #[test]
+    fn genesis_file_params_match_default() {
+        assert_eq!(
+            ConsensusParams::from_file("genesis.toml").unwrap(),
+            ConsensusParams::default()
+        );
+    }
+
+    #[test]
+    fn missing_params_keep_their_default() {
+        let p: ConsensusParams = toml::from_str("retarget_interval = 5\nmin_fee = 3").unwrap();
+
+        assert_eq!(
+            p,
+            ConsensusParams {
+                retarget_interval: 5,
+                min_fee: 3,
+                ..ConsensusParams::default()
+            }
+        );
+    }
+}
@@ -0,0 +1,287 @@
+use std::collections::HashSet;
+
+use bincode::{Decode, Encode, config};
+
+use crate::account::{Account, state_root};
+use crate::block::{Block, BlockError};
+use crate::storage::Storage;
+
+use super::error::LedgerError;
+use super::ledger::Ledger;
+
+/// Account state of the active chain at one block, from which a node can start
+/// without replaying the chain up to that block.
+///
+/// `blocks` ends with the block the state belongs to, whose header commits to
+/// `accounts`. The blocks before it are the ancestors needed to check the
+/// difficulty of the blocks that follow.
+#[derive(Debug, Clone, PartialEq, Encode, Decode)]
+pub struct Snapshot {
+    pub blocks: Vec<Block>,
+    pub accounts: Vec<Account>,
+}
+
+impl Snapshot {
+    /// Block the state belongs to.
+    pub fn block(&self) -> Option<&Block> {
+        self.blocks.last()
+    }
+
+    /// Checks that the blocks are linked and mined, and that the accounts
+    /// match the state root in the header of the last one.
+    ///
+    /// This does not tie the snapshot to any particular chain: callers must
+    /// also compare the hash of [`Snapshot::block`] with one they trust.
+    pub fn verify(&self) -> Result<(), LedgerError> {
+        let block = self.block().ok_or(LedgerError::EmptySnapshot)?;
+
+        for block in &self.blocks {
+            block.verify_hash()?;
+        }
+        for pair in self.blocks.windows(2) {
+            let (parent, child) = (&pair[0], &pair[1]);
+            if child.index() != parent.index() + 1 {
+                return Err(BlockError::InvalidIndex {
+                    got: child.index(),
+                    want: parent.index() + 1,
+                }
+                .into());
+            }
+            if child.previous_hash() != parent.hash() {
+                return Err(BlockError::InvalidPreviousHash {
+                    got: *child.previous_hash(),
+                    want: *parent.hash(),
+                }
+                .into());
+            }
+        }
+
+        let mut addresses = HashSet::new();
+        for account in &self.accounts {
+            if !addresses.insert(account.address()) {
+                return Err(LedgerError::DuplicateAccount(account.address()));
+            }
+        }
+
+        let root = state_root(&self.accounts);
+        if root != *block.state_root() {
+            return Err(BlockError::InvalidStateRoot {
+                got: root,
+                want: *block.state_root(),
+            }
+            .into());
+        }
+
+        Ok(())
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, LedgerError> {
+        let (snapshot, _): (Self, usize) = bincode::decode_from_slice(bytes, config::standard())?;
+        Ok(snapshot)
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, LedgerError> {
+        bincode::encode_to_vec(self, config::standard()).map_err(LedgerError::from)
+    }
+}
+
+impl<S: Storage> Ledger<S> {
+    /// Exports the state at height `index` of the active chain.
+    pub fn snapshot(&self, index: u64) -> Result<Snapshot, LedgerError> {
+        let block = self
+            .block_at(index)?
+            .ok_or(LedgerError::BlockNotFound(index))?;
+
+        let mut accounts = self.accounts_at(index)?;
+        accounts.sort_by(|a, b| a.address().as_ref().cmp(b.address().as_ref()));
+
+        let ancestors = self.params().retarget_interval.max(1) as usize;
+        let mut blocks = vec![block];
+        while blocks.len() < ancestors {
+            let oldest = &blocks[blocks.len() - 1];
+            if oldest.index() == 0 {
+                break;
+            }
+            match self.storage().block(oldest.previous_hash())? {
+                Some(parent) => blocks.push(parent),
+                None => break,
+            }
+        }
+        blocks.reverse();
+
+        Ok(Snapshot { blocks, accounts })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::account::Address;
+    use crate::ledger::ConsensusParams;
+    use crate::ledger::testing::{self, Key};
+    use crate::storage::{FileStorage, MemoryStorage};
+
+    use super::*;
+
+    fn params() -> ConsensusParams {
+        ConsensusParams {
+            retarget_interval: 3,
+            ..ConsensusParams::default()
+        }
+    }
+
+    /// A chain of `blocks` blocks in which `a` pays two other accounts.
+    fn chain(blocks: u64) -> (Ledger, Key) {
+        let a = Key::new();
+        let genesis = testing::genesis(&[(a.address, 1_000)]);
+        let mut ledger = Ledger::from_genesis(genesis, params()).unwrap();
+        for nonce in 0..blocks {
+            let to = Address::from([nonce as u8 % 2 + 1; 32]);
+            let t = a.transfer(to, 10, 1, nonce);
+            testing::mine(&mut ledger, Address::from([9u8; 32]), vec![t]).unwrap();
+        }
+        (ledger, a)
+    }
+
+    #[test]
+    fn exported_snapshot_verifies() {
+        let (ledger, _) = chain(4);
+        let snapshot = ledger.snapshot(4).unwrap();
+
+        snapshot.verify().unwrap();
+        assert_eq!(snapshot.block(), ledger.last().ok().as_ref());
+        assert_eq!(
+            snapshot.blocks.iter().map(Block::index).collect::<Vec<_>>(),
+            [2, 3, 4]
+        );
+        assert_eq!(
+            Snapshot::from_bytes(&snapshot.encode().unwrap()).unwrap(),
+            snapshot
+        );
+        assert!(matches!(
+            Snapshot {
+                blocks: vec![],
+                accounts: snapshot.accounts
+            }
+            .verify(),
+            Err(LedgerError::EmptySnapshot)
+        ));
+    }
+
+    #[test]
+    fn tampered_accounts_are_rejected() {
+        let (ledger, a) = chain(4);
+        let snapshot = ledger.snapshot(4).unwrap();
+
+        let mut tampered = snapshot.clone();
+        let account = tampered
+            .accounts
+            .iter_mut()
+            .find(|account| account.address() == a.address)
+            .unwrap();
+        account.balance += 1;
+        assert!(matches!(
+            tampered.verify(),
+            Err(LedgerError::BlockError(BlockError::InvalidStateRoot { .. }))
+        ));
+
+        let mut duplicated = snapshot.clone();
+        duplicated.accounts.push(duplicated.accounts[0]);
+        assert!(matches!(
+            duplicated.verify(),
+            Err(LedgerError::DuplicateAccount(address)) if address == snapshot.accounts[0].address()
+        ));
+    }
+
+    #[test]
+    fn broken_block_links_are_rejected() {
+        let (ledger, _) = chain(4);
+        let snapshot = ledger.snapshot(4).unwrap();
+
+        let mut gap = snapshot.clone();
+        gap.blocks.remove(1);
+        assert!(matches!(
+            gap.verify(),
+            Err(LedgerError::BlockError(BlockError::InvalidIndex {
+                got: 4,
+                want: 3
+            }))
+        ));
+
+        // Block 2 of another chain.
+        let (other, _) = chain(2);
+        let mut relinked = snapshot.clone();
+        relinked.blocks[0] = other.block_at(2).unwrap().unwrap();
+        assert!(matches!(
+            relinked.verify(),
+            Err(LedgerError::BlockError(
+                BlockError::InvalidPreviousHash { .. }
+            ))
+        ));
+    }
+
+    #[test]
+    fn bootstrapping_needs_empty_storage() {
+        let (ledger, _) = chain(2);
+        let snapshot = ledger.snapshot(2).unwrap();
+
+        let e = Ledger::from_snapshot(
+            ledger.storage().clone(),
+            ledger.genesis_config().clone(),
+            params(),
+            snapshot.clone(),
+        )
+        .unwrap_err();
+        assert!(matches!(e, LedgerError::NonEmptyStorage), "{e:?}");
+
+        Ledger::from_snapshot(
+            MemoryStorage::new(),
+            ledger.genesis_config().clone(),
+            params(),
+            snapshot,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn bootstrapped_ledger_follows_the_chain_and_reopens() {
+        let (ledger, a) = chain(7);
+        let dir = tempfile::tempdir().unwrap();
+        let genesis = ledger.genesis_config().clone();
+
+        let mut bootstrapped = Ledger::from_snapshot(
+            FileStorage::open(dir.path()).unwrap(),
+            genesis.clone(),
+            params(),
+            ledger.snapshot(4).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(bootstrapped.base(), 4);
+        assert_eq!(bootstrapped.height(), 4);
+
+        // Block 6 is retargeted from blocks 3 to 5, two of which only the
+        // snapshot provides.
+        for index in 5..=7 {
+            let block = ledger.block_at(index).unwrap().unwrap();
+            bootstrapped.append_block(block).unwrap();
+        }
+        assert_eq!(bootstrapped.last().unwrap(), ledger.last().unwrap());
+        assert_eq!(
+            bootstrapped.state_root().unwrap(),
+            ledger.state_root().unwrap()
+        );
+        drop(bootstrapped);
+
+        let reopened =
+            Ledger::open(FileStorage::open(dir.path()).unwrap(), genesis, params()).unwrap();
+        assert_eq!(reopened.base(), 4);
+        assert_eq!(reopened.height(), 7);
+        assert_eq!(reopened.last().unwrap(), ledger.last().unwrap());
+        assert_eq!(reopened.state_root().unwrap(), ledger.state_root().unwrap());
+        assert_eq!(
+            reopened.balance(a.address).unwrap(),
+            ledger.balance(a.address).unwrap()
+        );
+        assert_eq!(reopened.block_at(3).unwrap(), None);
+        assert_eq!(reopened.block_at(4).unwrap(), ledger.block_at(4).unwrap());
+    }
+}
@@ -0,0 +1,117 @@
+//! Fixtures shared by the ledger and storage tests.
+
+use pqcrypto::sign::falconpadded512::{self, SecretKey};
+use pqcrypto::traits::sign::PublicKey as _;
+
+use crate::account::{Address, PublicKey};
+use crate::block::Block;
+use crate::genesis::{Allocation, GenesisConfig, GenesisError};
+use crate::storage::Storage;
+use crate::transaction::{self, DEFAULT_CHAIN_ID, Transaction};
+
+use super::error::LedgerError;
+use super::ledger::Ledger;
+
+/// Keypair of a test account.
+pub(crate) struct Key {
+    pub public_key: PublicKey,
+    pub secret_key: SecretKey,
+    pub address: Address,
+}
+
+impl Key {
+    pub fn new() -> Self {
+        let (pk, secret_key) = falconpadded512::keypair();
+        let public_key: PublicKey = pk.as_bytes().try_into().unwrap();
+
+        Self {
+            public_key,
+            secret_key,
+            address: Address::from(public_key),
+        }
+    }
+
+    /// Transfer from this account, revealing its public key, signed for the
+    /// default chain.
+    pub fn transfer(&self, to: Address, amount: u64, fee: u64, nonce: u64) -> Transaction {
+        self.sign(Transaction::transfer(
+            self.public_key,
+            to,
+            amount,
+            fee,
+            nonce,
+        ))
+    }
+
+    pub fn sign(&self, t: Transaction) -> Transaction {
+        transaction::sign(t, DEFAULT_CHAIN_ID, &self.secret_key)
+    }
+}
+
+/// Genesis configuration minting `allocations`, with the hash its block mines to.
+pub(crate) fn genesis(allocations: &[(Address, u64)]) -> GenesisConfig {
+    let mut config = GenesisConfig {
+        allocations: allocations
+            .iter()
+            .map(|(address, amount)| Allocation {
+                address: address.to_string(),
+                amount: *amount,
+            })
+            .collect(),
+        ..GenesisConfig::default()
+    };
+
+    match config.block() {
+        Ok(_) => {}
+        Err(GenesisError::InvalidGenesisHash { got, .. }) => config.genesis_hash = got.to_string(),
+        Err(e) => panic!("invalid test genesis: {e}"),
+    }
+
+    config
+}
+
+/// Mines a block on top of the tip of `ledger` holding `transactions` behind a
+/// coinbase paying `producer`, without appending it.
+pub(crate) fn forge<S: Storage>(
+    ledger: &Ledger<S>,
+    producer: Address,
+    transactions: Vec<Transaction>,
+) -> Block {
+    let mut block = vec![ledger.coinbase(producer, &transactions).unwrap()];
+    block.extend(transactions);
+    ledger.forge(block).unwrap()
+}
+
+/// Like [`forge`], but stamps the block with `timestamp` instead of the
+/// current time.
+pub(crate) fn forge_at<S: Storage>(
+    ledger: &Ledger<S>,
+    producer: Address,
+    transactions: Vec<Transaction>,
+    timestamp: u128,
+) -> Block {
+    let mut transactions = transactions;
+    transactions.insert(0, ledger.coinbase(producer, &transactions).unwrap());
+
+    let parent = ledger.last().unwrap();
+    Block::forge(
+        parent.index() + 1,
+        timestamp,
+        *parent.hash(),
+        transactions.clone(),
+        ledger.state_root_after(&transactions).unwrap(),
+        ledger.next_difficulty(&parent).unwrap(),
+    )
+    .unwrap()
+}
+
+/// Mines and appends a block, see [`forge`].
+pub(crate) fn mine<S: Storage>(
+    ledger: &mut Ledger<S>,
+    producer: Address,
+    transactions: Vec<Transaction>,
+) -> Result<Block, LedgerError> {
+    let block = forge(ledger, producer, transactions);
+    ledger.append_block(block.clone())?;
+    Ok(block)
+}
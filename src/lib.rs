@@ -1,5 +1,8 @@
 pub mod account;
 pub mod block;
 pub mod client;
+pub mod genesis;
 pub mod ledger;
+pub mod mempool;
+pub mod storage;
 pub mod transaction;
@@ -0,0 +1,19 @@
+use thiserror::Error;
+
+use crate::account::Address;
+use crate::ledger::LedgerError;
+use crate::transaction::TransactionId;
+
+#[derive(Error, Debug)]
+pub enum MempoolError {
+    #[error("DuplicateTransaction: {0} is already in the mempool")]
+    DuplicateTransaction(TransactionId),
+    #[error("ReplacementUnderpriced: fee: {fee}, must exceed: {replaced_fee}")]
+    ReplacementUnderpriced { fee: u64, replaced_fee: u64 },
+    #[error("SenderLimit: {0} has too many pending transactions")]
+    SenderLimit(Address),
+    #[error("MempoolFull: fee rate too low to enter the mempool")]
+    MempoolFull,
+    #[error("InvalidTransaction: {0}")]
+    InvalidTransaction(LedgerError),
+}
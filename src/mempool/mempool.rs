@@ -0,0 +1,655 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use std::time::{Duration, Instant};
+
+use crate::account::Address;
+use crate::ledger::{Ledger, LedgerError};
+use crate::storage::Storage;
+use crate::transaction::{Transaction, TransactionId};
+
+use super::error::MempoolError;
+
+pub const MAX_TRANSACTIONS: usize = 10_000;
+pub const MAX_BYTES: usize = 32 * 1024 * 1024;
+pub const MAX_PER_SENDER: usize = 64;
+pub const EXPIRY: Duration = Duration::from_secs(60 * 60);
+
+/// Limits a [`Mempool`] enforces on what it holds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MempoolConfig {
+    pub max_transactions: usize,
+    /// Total encoded size of the pooled transactions.
+    pub max_bytes: usize,
+    /// Number of pending transactions a single sender may have.
+    pub max_per_sender: usize,
+    /// Time after which a transaction that was not mined is dropped.
+    pub expiry: Duration,
+}
+
+impl Default for MempoolConfig {
+    fn default() -> Self {
+        Self {
+            max_transactions: MAX_TRANSACTIONS,
+            max_bytes: MAX_BYTES,
+            max_per_sender: MAX_PER_SENDER,
+            expiry: EXPIRY,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    transaction: Transaction,
+    size: usize,
+    added: Instant,
+}
+
+impl Entry {
+    fn fee_rate(&self) -> FeeRate {
+        FeeRate {
+            fee: self.transaction.fee,
+            size: self.size,
+        }
+    }
+}
+
+/// Fee per encoded byte, compared without rounding.
+#[derive(Debug, Clone, Copy)]
+struct FeeRate {
+    fee: u64,
+    size: usize,
+}
+
+impl Ord for FeeRate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.fee as u128 * other.size as u128).cmp(&(other.fee as u128 * self.size as u128))
+    }
+}
+
+impl PartialOrd for FeeRate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for FeeRate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for FeeRate {}
+
+/// Transactions submitted to this node and not yet on the active chain.
+///
+/// Every pooled transaction is valid on top of the current tip, after the
+/// sender's pooled transactions with lower nonces. Each sender's
+/// transactions are therefore kept as a gapless run of nonces starting at the
+/// sender's on-chain nonce, and are only ever dropped from the end of that run.
+#[derive(Debug, Clone, Default)]
+pub struct Mempool {
+    config: MempoolConfig,
+    entries: HashMap<TransactionId, Entry>,
+    senders: HashMap<Address, BTreeMap<u64, TransactionId>>,
+    bytes: usize,
+}
+
+impl Mempool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_config(config: MempoolConfig) -> Self {
+        Self {
+            config,
+            ..Self::default()
+        }
+    }
+
+    pub fn config(&self) -> &MempoolConfig {
+        &self.config
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Total encoded size of the pooled transactions.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn contains(&self, id: &TransactionId) -> bool {
+        self.entries.contains_key(id)
+    }
+
+    pub fn get(&self, id: &TransactionId) -> Option<&Transaction> {
+        self.entries.get(id).map(|entry| &entry.transaction)
+    }
+
+    /// Nonce the next transaction from `address` must carry to follow both
+    /// the chain and the sender's pooled transactions.
+    pub fn pending_nonce<S: Storage>(
+        &self,
+        ledger: &Ledger<S>,
+        address: Address,
+    ) -> Result<u64, LedgerError> {
+        match self
+            .senders
+            .get(&address)
+            .and_then(|queue| queue.keys().next_back())
+        {
+            Some(nonce) => Ok(nonce + 1),
+            None => ledger.nonce(address),
+        }
+    }
+
+    /// Validates `t` against `ledger` and the sender's pooled transactions,
+    /// and adds it to the pool.
+    ///
+    /// A transaction reusing the nonce of a pooled one replaces it if it pays
+    /// a higher fee. When the pool is full, transactions with the lowest fee
+    /// rate are evicted to make room. If that would evict `t` itself, the pool
+    /// is left as it was, including any transaction `t` was to replace.
+    pub fn insert<S: Storage>(
+        &mut self,
+        ledger: &Ledger<S>,
+        t: Transaction,
+    ) -> Result<TransactionId, MempoolError> {
+        let id = t.id();
+        if self.entries.contains_key(&id) {
+            return Err(MempoolError::DuplicateTransaction(id));
+        }
+
+        self.prune_expired();
+
+        let queue = self
+            .senders
+            .get(&t.from_address)
+            .cloned()
+            .unwrap_or_default();
+        let replaced = queue.get(&t.nonce).copied();
+        match replaced.and_then(|replaced| self.entries.get(&replaced)) {
+            Some(entry) if entry.transaction.fee >= t.fee => {
+                return Err(MempoolError::ReplacementUnderpriced {
+                    fee: t.fee,
+                    replaced_fee: entry.transaction.fee,
+                });
+            }
+            None if queue.len() >= self.config.max_per_sender => {
+                return Err(MempoolError::SenderLimit(t.from_address));
+            }
+            _ => {}
+        }
+
+        let mut candidates: Vec<Transaction> = queue
+            .range(..t.nonce)
+            .map(|(_, id)| self.entries[id].transaction)
+            .collect();
+        candidates.push(t);
+        let report = ledger.select_transactions(candidates);
+        if let Some(rejection) = report
+            .rejected
+            .into_iter()
+            .find(|rejection| rejection.transaction == t)
+        {
+            return Err(MempoolError::InvalidTransaction(rejection.reason));
+        }
+
+        // Everything dropped to make room for `t`, put back if `t` does not
+        // stay either.
+        let mut dropped = Vec::new();
+        if let Some(replaced) = replaced {
+            dropped.extend(self.remove(&replaced).map(|entry| (replaced, entry)));
+        }
+        self.add(id, t);
+
+        // A replacement may spend more than the transaction it replaced,
+        // leaving the sender unable to fund its later nonces.
+        if replaced.is_some() {
+            dropped.extend(self.recheck_sender(ledger, t.from_address));
+        }
+
+        dropped.extend(self.enforce_limits());
+        if !self.entries.contains_key(&id) {
+            for (dropped, entry) in dropped {
+                if dropped != id {
+                    self.put(dropped, entry);
+                }
+            }
+            return Err(MempoolError::MempoolFull);
+        }
+
+        Ok(id)
+    }
+
+    /// Highest fee rate set of at most `limit` transactions that can be mined
+    /// together, in an order a block may include them.
+    ///
+    /// Senders are served in order of the fee rate of their next pending
+    /// transaction, so each sender's transactions come out in nonce order.
+    pub fn select(&self, limit: usize) -> Vec<Transaction> {
+        let queues: Vec<Vec<&Entry>> = self
+            .senders
+            .values()
+            .map(|queue| queue.values().map(|id| &self.entries[id]).collect())
+            .collect();
+
+        let mut heap: BinaryHeap<(FeeRate, usize, usize)> = queues
+            .iter()
+            .enumerate()
+            .filter_map(|(sender, queue)| Some((queue.first()?.fee_rate(), sender, 0)))
+            .collect();
+
+        let mut selected = Vec::new();
+        while selected.len() < limit {
+            let Some((_, sender, position)) = heap.pop() else {
+                break;
+            };
+
+            selected.push(queues[sender][position].transaction);
+            if let Some(next) = queues[sender].get(position + 1) {
+                heap.push((next.fee_rate(), sender, position + 1));
+            }
+        }
+
+        selected
+    }
+
+    /// Brings the pool in line with `ledger` after blocks were appended or the
+    /// chain reorganised.
+    ///
+    /// Mined and expired transactions are dropped along with any that no
+    /// longer apply, and transactions orphaned by a reorganisation are taken
+    /// from the ledger and offered to the pool again.
+    pub fn sync<S: Storage>(&mut self, ledger: &mut Ledger<S>) -> Result<(), LedgerError> {
+        self.prune_expired();
+
+        let senders: Vec<Address> = self.senders.keys().copied().collect();
+        for address in senders {
+            let nonce = ledger.nonce(address)?;
+            let mined: Vec<TransactionId> = self.senders[&address]
+                .range(..nonce)
+                .map(|(_, id)| *id)
+                .collect();
+            for id in mined {
+                self.remove(&id);
+            }
+        }
+
+        // Orphans go back before senders are rechecked, as pooled
+        // transactions may follow on from them.
+        for t in ledger.take_pending() {
+            // Orphans that no longer apply or fit are simply dropped.
+            let _ = self.insert(ledger, t);
+        }
+
+        let senders: Vec<Address> = self.senders.keys().copied().collect();
+        for address in senders {
+            self.recheck_sender(ledger, address);
+        }
+
+        Ok(())
+    }
+
+    /// Drops transactions that have waited longer than the configured expiry,
+    /// along with the later nonces of their senders.
+    pub fn prune_expired(&mut self) {
+        let expired: Vec<(Address, u64)> = self
+            .entries
+            .values()
+            .filter(|entry| entry.added.elapsed() >= self.config.expiry)
+            .map(|entry| (entry.transaction.from_address, entry.transaction.nonce))
+            .collect();
+
+        for (address, nonce) in expired {
+            self.truncate_sender(address, nonce);
+        }
+    }
+
+    /// Replays the pooled transactions of `address` on top of `ledger` and
+    /// drops the first one that fails along with every later nonce. Returns
+    /// the dropped entries.
+    fn recheck_sender<S: Storage>(
+        &mut self,
+        ledger: &Ledger<S>,
+        address: Address,
+    ) -> Vec<(TransactionId, Entry)> {
+        let Some(queue) = self.senders.get(&address) else {
+            return Vec::new();
+        };
+
+        let candidates = queue
+            .values()
+            .map(|id| self.entries[id].transaction)
+            .collect();
+        let report = ledger.select_transactions(candidates);
+
+        match report
+            .rejected
+            .iter()
+            .map(|rejection| rejection.transaction.nonce)
+            .min()
+        {
+            Some(nonce) => self.truncate_sender(address, nonce),
+            None => Vec::new(),
+        }
+    }
+
+    /// Evicts the lowest fee rate transactions until the pool is within its
+    /// limits. Only the last nonce of a sender is eligible, so that no sender
+    /// is left with a gap. Returns the evicted entries.
+    fn enforce_limits(&mut self) -> Vec<(TransactionId, Entry)> {
+        let mut evicted = Vec::new();
+        while self.entries.len() > self.config.max_transactions
+            || self.bytes > self.config.max_bytes
+        {
+            let Some(id) = self
+                .senders
+                .values()
+                .filter_map(|queue| queue.values().next_back())
+                .min_by_key(|id| {
+                    (
+                        self.entries[id].fee_rate(),
+                        std::cmp::Reverse(self.entries[id].added),
+                    )
+                })
+                .copied()
+            else {
+                break;
+            };
+
+            evicted.extend(self.remove(&id).map(|entry| (id, entry)));
+        }
+
+        evicted
+    }
+
+    fn add(&mut self, id: TransactionId, t: Transaction) {
+        let size = bincode::encode_to_vec(t, bincode::config::standard())
+            .expect("Transaction encoding cannot fail")
+            .len();
+
+        self.put(
+            id,
+            Entry {
+                transaction: t,
+                size,
+                added: Instant::now(),
+            },
+        );
+    }
+
+    fn put(&mut self, id: TransactionId, entry: Entry) {
+        self.bytes += entry.size;
+        self.senders
+            .entry(entry.transaction.from_address)
+            .or_default()
+            .insert(entry.transaction.nonce, id);
+        self.entries.insert(id, entry);
+    }
+
+    /// Removes the transactions of `address` with a nonce of `nonce` or more,
+    /// returning their entries.
+    fn truncate_sender(&mut self, address: Address, nonce: u64) -> Vec<(TransactionId, Entry)> {
+        let Some(queue) = self.senders.get(&address) else {
+            return Vec::new();
+        };
+
+        let ids: Vec<TransactionId> = queue.range(nonce..).map(|(_, id)| *id).collect();
+        ids.into_iter()
+            .filter_map(|id| Some((id, self.remove(&id)?)))
+            .collect()
+    }
+
+    fn remove(&mut self, id: &TransactionId) -> Option<Entry> {
+        let entry = self.entries.remove(id)?;
+        self.bytes -= entry.size;
+
+        let address = entry.transaction.from_address;
+        if let Some(queue) = self.senders.get_mut(&address) {
+            queue.remove(&entry.transaction.nonce);
+            if queue.is_empty() {
+                self.senders.remove(&address);
+            }
+        }
+
+        Some(entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use crate::ledger::testing::{self, Key};
+    use crate::ledger::{ConsensusParams, Ledger};
+
+    use super::*;
+
+    fn funded(keys: &[&Key]) -> Ledger {
+        let allocations: Vec<(Address, u64)> = keys.iter().map(|k| (k.address, 1_000)).collect();
+        Ledger::from_genesis(testing::genesis(&allocations), ConsensusParams::default()).unwrap()
+    }
+
+    fn size(t: &Transaction) -> usize {
+        bincode::encode_to_vec(t, bincode::config::standard())
+            .unwrap()
+            .len()
+    }
+
+    fn to() -> Address {
+        Address::from([7u8; 32])
+    }
+
+    fn producer() -> Address {
+        Address::from([9u8; 32])
+    }
+
+    #[test]
+    fn duplicates_and_nonce_gaps_are_rejected() {
+        let a = Key::new();
+        let ledger = funded(&[&a]);
+        let mut pool = Mempool::new();
+
+        let t = a.transfer(to(), 10, 1, 0);
+        assert_eq!(pool.insert(&ledger, t).unwrap(), t.id());
+        assert!(matches!(
+            pool.insert(&ledger, t),
+            Err(MempoolError::DuplicateTransaction(id)) if id == t.id()
+        ));
+        assert!(matches!(
+            pool.insert(&ledger, a.transfer(to(), 10, 1, 2)),
+            Err(MempoolError::InvalidTransaction(_))
+        ));
+        assert_eq!(pool.len(), 1);
+        assert_eq!(pool.bytes(), size(&t));
+        assert_eq!(pool.pending_nonce(&ledger, a.address).unwrap(), 1);
+    }
+
+    #[test]
+    fn senders_are_limited() {
+        let a = Key::new();
+        let ledger = funded(&[&a]);
+        let mut pool = Mempool::with_config(MempoolConfig {
+            max_per_sender: 2,
+            ..MempoolConfig::default()
+        });
+
+        pool.insert(&ledger, a.transfer(to(), 10, 1, 0)).unwrap();
+        pool.insert(&ledger, a.transfer(to(), 10, 1, 1)).unwrap();
+        assert!(matches!(
+            pool.insert(&ledger, a.transfer(to(), 10, 1, 2)),
+            Err(MempoolError::SenderLimit(address)) if address == a.address
+        ));
+
+        // Replacing a pooled nonce does not count against the limit.
+        pool.insert(&ledger, a.transfer(to(), 10, 2, 1)).unwrap();
+        assert_eq!(pool.len(), 2);
+    }
+
+    #[test]
+    fn higher_fee_replaces_pooled_nonce() {
+        let a = Key::new();
+        let ledger = funded(&[&a]);
+        let mut pool = Mempool::new();
+
+        let first = a.transfer(to(), 100, 1, 0);
+        let second = a.transfer(to(), 100, 1, 1);
+        pool.insert(&ledger, first).unwrap();
+        pool.insert(&ledger, second).unwrap();
+
+        assert!(matches!(
+            pool.insert(&ledger, a.transfer(to(), 50, 1, 0)),
+            Err(MempoolError::ReplacementUnderpriced {
+                fee: 1,
+                replaced_fee: 1
+            })
+        ));
+
+        let bump = a.transfer(to(), 100, 2, 0);
+        pool.insert(&ledger, bump).unwrap();
+        assert!(pool.contains(&bump.id()));
+        assert!(!pool.contains(&first.id()));
+        assert!(pool.contains(&second.id()));
+
+        // A replacement leaving later nonces unfunded drops them.
+        let drain = a.transfer(to(), 990, 3, 0);
+        pool.insert(&ledger, drain).unwrap();
+        assert!(pool.contains(&drain.id()));
+        assert!(!pool.contains(&second.id()));
+        assert_eq!(pool.len(), 1);
+        assert_eq!(pool.bytes(), size(&drain));
+    }
+
+    #[test]
+    fn lowest_fee_rate_is_evicted_when_full() {
+        let keys = [Key::new(), Key::new(), Key::new(), Key::new()];
+        let ledger = funded(&keys.iter().collect::<Vec<_>>());
+        let mut pool = Mempool::with_config(MempoolConfig {
+            max_transactions: 2,
+            ..MempoolConfig::default()
+        });
+
+        let cheap = keys[0].transfer(to(), 10, 1, 0);
+        let rich = keys[1].transfer(to(), 10, 3, 0);
+        let middle = keys[2].transfer(to(), 10, 2, 0);
+        pool.insert(&ledger, cheap).unwrap();
+        pool.insert(&ledger, rich).unwrap();
+        pool.insert(&ledger, middle).unwrap();
+        assert!(!pool.contains(&cheap.id()));
+        assert!(pool.contains(&rich.id()) && pool.contains(&middle.id()));
+
+        assert!(matches!(
+            pool.insert(&ledger, keys[3].transfer(to(), 10, 1, 0)),
+            Err(MempoolError::MempoolFull)
+        ));
+        assert_eq!(pool.len(), 2);
+    }
+
+    #[test]
+    fn evicted_replacement_keeps_the_original() {
+        let (a, b) = (Key::new(), Key::new());
+        let ledger = funded(&[&a, &b]);
+
+        let original = a.transfer(to(), 1, 1, 0);
+        let rich = b.transfer(to(), 1, 500, 0);
+        let mut pool = Mempool::with_config(MempoolConfig {
+            max_bytes: size(&original) + size(&rich),
+            ..MempoolConfig::default()
+        });
+        pool.insert(&ledger, original).unwrap();
+        pool.insert(&ledger, rich).unwrap();
+
+        // Pays a higher fee, but is larger and has the lowest fee rate.
+        let replacement = a.transfer(to(), 900, 2, 0);
+        assert!(size(&replacement) > size(&original));
+        assert!(matches!(
+            pool.insert(&ledger, replacement),
+            Err(MempoolError::MempoolFull)
+        ));
+
+        assert!(pool.contains(&original.id()));
+        assert!(pool.contains(&rich.id()));
+        assert!(!pool.contains(&replacement.id()));
+        assert_eq!(pool.bytes(), size(&original) + size(&rich));
+        assert_eq!(pool.pending_nonce(&ledger, a.address).unwrap(), 1);
+    }
+
+    #[test]
+    fn expired_transactions_are_pruned() {
+        let a = Key::new();
+        let ledger = funded(&[&a]);
+        let mut pool = Mempool::with_config(MempoolConfig {
+            expiry: Duration::from_millis(200),
+            ..MempoolConfig::default()
+        });
+
+        pool.insert(&ledger, a.transfer(to(), 10, 1, 0)).unwrap();
+        pool.insert(&ledger, a.transfer(to(), 10, 1, 1)).unwrap();
+        pool.prune_expired();
+        assert_eq!(pool.len(), 2);
+
+        thread::sleep(Duration::from_millis(250));
+        pool.prune_expired();
+        assert!(pool.is_empty());
+        assert_eq!(pool.bytes(), 0);
+    }
+
+    #[test]
+    fn select_serves_senders_by_fee_rate_in_nonce_order() {
+        let (a, b) = (Key::new(), Key::new());
+        let ledger = funded(&[&a, &b]);
+        let mut pool = Mempool::new();
+
+        let a0 = a.transfer(to(), 10, 1, 0);
+        let a1 = a.transfer(to(), 10, 10, 1);
+        let b0 = b.transfer(to(), 10, 5, 0);
+        for t in [a0, a1, b0] {
+            pool.insert(&ledger, t).unwrap();
+        }
+
+        assert_eq!(pool.select(3), vec![b0, a0, a1]);
+        assert_eq!(pool.select(2), vec![b0, a0]);
+        assert!(
+            ledger
+                .select_transactions(pool.select(3))
+                .rejected
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn sync_drops_mined_and_restores_orphaned_transactions() {
+        let a = Key::new();
+        let mut ledger = funded(&[&a]);
+        let mut fork = ledger.clone();
+        let mut pool = Mempool::new();
+
+        let t = a.transfer(to(), 10, 1, 0);
+        let later = a.transfer(to(), 10, 1, 1);
+        pool.insert(&ledger, t).unwrap();
+        pool.insert(&ledger, later).unwrap();
+
+        testing::mine(&mut ledger, producer(), vec![t]).unwrap();
+        pool.sync(&mut ledger).unwrap();
+        assert!(!pool.contains(&t.id()));
+        assert!(pool.contains(&later.id()));
+
+        // A heavier branch without `t` detaches the block that mined it.
+        for _ in 0..2 {
+            let block = testing::mine(&mut fork, producer(), vec![]).unwrap();
+            ledger.append_block(block).unwrap();
+        }
+        assert_eq!(ledger.nonce(a.address).unwrap(), 0);
+        pool.sync(&mut ledger).unwrap();
+        assert!(pool.contains(&t.id()));
+        assert!(pool.contains(&later.id()));
+        assert!(ledger.pending().is_empty());
+        assert_eq!(pool.select(2), vec![t, later]);
+    }
+}
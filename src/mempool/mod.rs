@@ -0,0 +1,5 @@
+mod error;
+mod mempool;
+
+pub use error::MempoolError;
+pub use mempool::{Mempool, MempoolConfig};
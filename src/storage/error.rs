@@ -0,0 +1,14 @@
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum StorageError {
+    #[error("CorruptRecord: log record at offset {0} is corrupt or does not match its index")]
+    CorruptRecord(u64),
+
+    #[error("IOError: {0}")]
+    IOError(#[from] std::io::Error),
+    #[error("EncodeError: {0}")]
+    EncodeError(#[from] bincode::error::EncodeError),
+    #[error("DecodeError: {0}")]
+    DecodeError(#[from] bincode::error::DecodeError),
+}
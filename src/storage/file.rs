@@ -0,0 +1,879 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use bincode::{Decode, Encode};
+
+use crate::account::{Account, Address, NodeKey, StateNode};
+use crate::block::{Block, BlockHash};
+use crate::ledger::ConsensusParams;
+use crate::transaction::TransactionId;
+
+use super::error::StorageError;
+use super::log::{BlockLog, RecordLog, create_dir};
+use super::storage::{StateUndo, Storage};
+
+/// Number of commits between two compactions of the state log.
+pub const SNAPSHOT_INTERVAL: u64 = 100;
+
+/// Records written per flush while compacting.
+const COMPACTION_BATCH: usize = 1024;
+
+const BLOCKS_FILE: &str = "blocks.log";
+const STATE_FILE: &str = "state.log";
+const STATE_TMP_FILE: &str = "state.log.tmp";
+
+#[derive(Debug, Encode, Decode)]
+enum StateRecord {
+    Account(Box<Account>),
+    AccountRemoved(Address),
+    Undo(BlockHash, StateUndo),
+    UndoRemoved(BlockHash),
+    BlockRemoved(BlockHash),
+    Commit(BlockHash),
+    Node(NodeKey, StateNode),
+    NodeRemoved(NodeKey),
+    Params(ConsensusParams),
+    Work(BlockHash, u128),
+    WorkRemoved(BlockHash),
+    Active(u64, BlockHash),
+    ActiveRemoved(u64),
+    Base(u64),
+    Transaction(TransactionId, u64, usize),
+    TransactionRemoved(TransactionId),
+}
+
+/// Effect of a state record on the index, without its payload.
+enum Change {
+    Account(Address, Option<u64>),
+    Node(NodeKey, Option<u64>),
+    Undo(BlockHash, Option<u64>),
+    BlockRemoved(BlockHash),
+    Params(u64),
+    Work(BlockHash, Option<u64>),
+    Active(u64, Option<u64>),
+    Base(u64),
+    Transaction(TransactionId, Option<u64>),
+    Commit(BlockHash),
+}
+
+impl Change {
+    fn of(offset: u64, record: &StateRecord) -> Self {
+        match record {
+            StateRecord::Account(account) => Self::Account(account.address(), Some(offset)),
+            StateRecord::AccountRemoved(address) => Self::Account(*address, None),
+            StateRecord::Node(key, _) => Self::Node(*key, Some(offset)),
+            StateRecord::NodeRemoved(key) => Self::Node(*key, None),
+            StateRecord::Undo(hash, _) => Self::Undo(*hash, Some(offset)),
+            StateRecord::UndoRemoved(hash) => Self::Undo(*hash, None),
+            StateRecord::BlockRemoved(hash) => Self::BlockRemoved(*hash),
+            StateRecord::Params(_) => Self::Params(offset),
+            StateRecord::Work(hash, _) => Self::Work(*hash, Some(offset)),
+            StateRecord::WorkRemoved(hash) => Self::Work(*hash, None),
+            StateRecord::Active(height, _) => Self::Active(*height, Some(offset)),
+            StateRecord::ActiveRemoved(height) => Self::Active(*height, None),
+            StateRecord::Base(base) => Self::Base(*base),
+            StateRecord::Transaction(id, _, _) => Self::Transaction(*id, Some(offset)),
+            StateRecord::TransactionRemoved(id) => Self::Transaction(*id, None),
+            StateRecord::Commit(tip) => Self::Commit(*tip),
+        }
+    }
+}
+
+/// Offsets of the live records of the state log.
+#[derive(Debug, Default)]
+struct StateIndex {
+    accounts: HashMap<Address, u64>,
+    nodes: HashMap<NodeKey, u64>,
+    undo: HashMap<BlockHash, u64>,
+    work: HashMap<BlockHash, u64>,
+    active: HashMap<u64, u64>,
+    transactions: HashMap<TransactionId, u64>,
+    removed_blocks: HashSet<BlockHash>,
+    params: Option<u64>,
+    base: Option<u64>,
+    tip: Option<BlockHash>,
+}
+
+impl StateIndex {
+    fn apply(&mut self, change: Change) {
+        match change {
+            Change::Account(address, Some(offset)) => {
+                self.accounts.insert(address, offset);
+            }
+            Change::Account(address, None) => {
+                self.accounts.remove(&address);
+            }
+            Change::Node(key, Some(offset)) => {
+                self.nodes.insert(key, offset);
+            }
+            Change::Node(key, None) => {
+                self.nodes.remove(&key);
+            }
+            Change::Undo(hash, Some(offset)) => {
+                self.undo.insert(hash, offset);
+            }
+            Change::Undo(hash, None) => {
+                self.undo.remove(&hash);
+            }
+            Change::BlockRemoved(hash) => {
+                self.removed_blocks.insert(hash);
+            }
+            Change::Params(offset) => self.params = Some(offset),
+            Change::Work(hash, Some(offset)) => {
+                self.work.insert(hash, offset);
+            }
+            Change::Work(hash, None) => {
+                self.work.remove(&hash);
+            }
+            Change::Active(height, Some(offset)) => {
+                self.active.insert(height, offset);
+            }
+            Change::Active(height, None) => {
+                self.active.remove(&height);
+            }
+            Change::Base(base) => self.base = Some(base),
+            Change::Transaction(id, Some(offset)) => {
+                self.transactions.insert(id, offset);
+            }
+            Change::Transaction(id, None) => {
+                self.transactions.remove(&id);
+            }
+            Change::Commit(tip) => self.tip = Some(tip),
+        }
+    }
+}
+
+/// Storage kept in a data directory.
+///
+/// Blocks are appended to `blocks.log` as soon as they are stored. Every other
+/// write, from accounts and state tree nodes to the block tree and the active
+/// chain, is buffered in memory until [`Storage::commit`], which appends them
+/// to `state.log` in a single flushed write, so the state on disk always
+/// matches a committed tip. Records left after the last commit by a crash are
+/// dropped on open. Only the offsets of the live records are kept in memory.
+///
+/// The state log only grows, so every `snapshot_interval` commits it is
+/// replaced by a snapshot holding just the live records.
+#[derive(Debug)]
+pub struct FileStorage {
+    dir: PathBuf,
+    blocks: BlockLog,
+    state: RecordLog<StateRecord>,
+    index: StateIndex,
+    dirty_accounts: HashMap<Address, Option<Account>>,
+    dirty_nodes: HashMap<NodeKey, Option<StateNode>>,
+    dirty_undo: HashMap<BlockHash, Option<StateUndo>>,
+    dirty_blocks: Vec<BlockHash>,
+    dirty_work: HashMap<BlockHash, Option<u128>>,
+    dirty_active: HashMap<u64, Option<BlockHash>>,
+    dirty_transactions: HashMap<TransactionId, Option<(u64, usize)>>,
+    dirty_params: Option<ConsensusParams>,
+    dirty_base: Option<u64>,
+    truncated: u64,
+    snapshot_interval: u64,
+    since_snapshot: u64,
+}
+
+impl FileStorage {
+    /// Opens the data directory `dir`, creating it if needed, and recovers
+    /// from an interrupted write.
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self, StorageError> {
+        let dir = dir.as_ref().to_path_buf();
+        create_dir(&dir)?;
+        let mut blocks = BlockLog::open(dir.join(BLOCKS_FILE))?;
+
+        // Records only take effect once the commit that ends their batch is
+        // read.
+        let mut index = StateIndex::default();
+        let mut batch = Vec::new();
+        let mut batch_start = None;
+        let mut state = RecordLog::open(dir.join(STATE_FILE), |offset, record| {
+            batch_start.get_or_insert(offset);
+            let change = Change::of(offset, &record);
+            if let Change::Commit(_) = change {
+                batch.drain(..).for_each(|change| index.apply(change));
+                index.apply(change);
+                batch_start = None;
+            } else {
+                batch.push(change);
+            }
+        })?;
+
+        let mut truncated = blocks.truncated() + state.truncated();
+        if let Some(start) = batch_start {
+            truncated += state.len() - start;
+            state.truncate(start)?;
+        }
+        for hash in &index.removed_blocks {
+            blocks.forget(hash);
+        }
+
+        Ok(Self {
+            dir,
+            blocks,
+            state,
+            index,
+            dirty_accounts: HashMap::new(),
+            dirty_nodes: HashMap::new(),
+            dirty_undo: HashMap::new(),
+            dirty_blocks: Vec::new(),
+            dirty_work: HashMap::new(),
+            dirty_active: HashMap::new(),
+            dirty_transactions: HashMap::new(),
+            dirty_params: None,
+            dirty_base: None,
+            truncated,
+            snapshot_interval: SNAPSHOT_INTERVAL,
+            since_snapshot: 0,
+        })
+    }
+
+    pub fn with_snapshot_interval(mut self, snapshot_interval: u64) -> Self {
+        self.snapshot_interval = snapshot_interval;
+        self
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Bytes of torn or uncommitted records dropped when the directory was
+    /// opened.
+    pub fn truncated(&self) -> u64 {
+        self.truncated
+    }
+
+    pub fn blocks(&self) -> &BlockLog {
+        &self.blocks
+    }
+
+    /// Replaces the state log with one holding only its live records.
+    ///
+    /// The new log is written to a temporary file that is flushed and then
+    /// renamed over the old one, so a crash leaves either of them intact.
+    /// Uncommitted writes are left buffered.
+    pub fn snapshot(&mut self) -> Result<(), StorageError> {
+        let Some(tip) = self.index.tip else {
+            return Ok(());
+        };
+
+        let tmp = self.dir.join(STATE_TMP_FILE);
+        match fs::remove_file(&tmp) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        let mut log = RecordLog::open(&tmp, |_, _: StateRecord| {})?;
+
+        let mut index = StateIndex::default();
+        let mut batch = Vec::with_capacity(COMPACTION_BATCH);
+        let offsets = self
+            .index
+            .accounts
+            .values()
+            .chain(self.index.nodes.values())
+            .chain(self.index.undo.values())
+            .chain(self.index.work.values())
+            .chain(self.index.active.values())
+            .chain(self.index.transactions.values())
+            .chain(&self.index.params);
+        for record in self.state.read_each(offsets.copied())? {
+            batch.push(record?);
+            if batch.len() == COMPACTION_BATCH {
+                write_batch(&mut log, &mut index, &batch)?;
+                batch.clear();
+            }
+        }
+        batch.extend(
+            self.index
+                .removed_blocks
+                .iter()
+                .map(|hash| StateRecord::BlockRemoved(*hash)),
+        );
+        batch.extend(self.index.base.map(StateRecord::Base));
+        batch.push(StateRecord::Commit(tip));
+        write_batch(&mut log, &mut index, &batch)?;
+
+        log.rename(self.dir.join(STATE_FILE))?;
+        self.state = log;
+        self.index = index;
+        self.since_snapshot = 0;
+
+        Ok(())
+    }
+}
+
+fn write_batch(
+    log: &mut RecordLog<StateRecord>,
+    index: &mut StateIndex,
+    records: &[StateRecord],
+) -> Result<(), StorageError> {
+    let offsets = log.append(records)?;
+    for (offset, record) in offsets.into_iter().zip(records) {
+        index.apply(Change::of(offset, record));
+    }
+
+    Ok(())
+}
+
+impl Storage for FileStorage {
+    fn account(&self, address: &Address) -> Result<Option<Account>, StorageError> {
+        if let Some(account) = self.dirty_accounts.get(address) {
+            return Ok(*account);
+        }
+
+        match self.index.accounts.get(address) {
+            Some(offset) => match self.state.read_at(*offset)? {
+                StateRecord::Account(account) => Ok(Some(*account)),
+                _ => Err(StorageError::CorruptRecord(*offset)),
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn put_account(&mut self, account: Account) -> Result<(), StorageError> {
+        self.dirty_accounts.insert(account.address(), Some(account));
+        Ok(())
+    }
+
+    fn remove_account(&mut self, address: &Address) -> Result<(), StorageError> {
+        self.dirty_accounts.insert(*address, None);
+        Ok(())
+    }
+
+    fn accounts(&self) -> Result<Vec<Account>, StorageError> {
+        let mut accounts: Vec<Account> = self.dirty_accounts.values().flatten().copied().collect();
+        let offsets: Vec<u64> = self
+            .index
+            .accounts
+            .iter()
+            .filter(|(address, _)| !self.dirty_accounts.contains_key(*address))
+            .map(|(_, offset)| *offset)
+            .collect();
+        let records = self.state.read_each(offsets.iter().copied())?;
+        for (offset, record) in offsets.iter().zip(records) {
+            match record? {
+                StateRecord::Account(account) => accounts.push(*account),
+                _ => return Err(StorageError::CorruptRecord(*offset)),
+            }
+        }
+
+        Ok(accounts)
+    }
+
+    fn node(&self, key: &NodeKey) -> Result<Option<StateNode>, StorageError> {
+        if let Some(node) = self.dirty_nodes.get(key) {
+            return Ok(*node);
+        }
+
+        match self.index.nodes.get(key) {
+            Some(offset) => match self.state.read_at(*offset)? {
+                StateRecord::Node(_, node) => Ok(Some(node)),
+                _ => Err(StorageError::CorruptRecord(*offset)),
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn put_node(&mut self, key: NodeKey, node: StateNode) -> Result<(), StorageError> {
+        self.dirty_nodes.insert(key, Some(node));
+        Ok(())
+    }
+
+    fn remove_node(&mut self, key: &NodeKey) -> Result<(), StorageError> {
+        self.dirty_nodes.insert(*key, None);
+        Ok(())
+    }
+
+    fn block(&self, hash: &BlockHash) -> Result<Option<Block>, StorageError> {
+        self.blocks.get(hash)
+    }
+
+    fn put_block(&mut self, block: &Block) -> Result<(), StorageError> {
+        self.blocks.append(block)
+    }
+
+    fn remove_block(&mut self, hash: &BlockHash) -> Result<(), StorageError> {
+        if self.blocks.contains(hash) {
+            self.blocks.forget(hash);
+            self.dirty_blocks.push(*hash);
+        }
+        Ok(())
+    }
+
+    fn block_hashes(&self) -> Result<Vec<BlockHash>, StorageError> {
+        Ok(self.blocks.hashes().to_vec())
+    }
+
+    fn work(&self, hash: &BlockHash) -> Result<Option<u128>, StorageError> {
+        if let Some(work) = self.dirty_work.get(hash) {
+            return Ok(*work);
+        }
+
+        match self.index.work.get(hash) {
+            Some(offset) => match self.state.read_at(*offset)? {
+                StateRecord::Work(_, work) => Ok(Some(work)),
+                _ => Err(StorageError::CorruptRecord(*offset)),
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn put_work(&mut self, hash: &BlockHash, work: u128) -> Result<(), StorageError> {
+        self.dirty_work.insert(*hash, Some(work));
+        Ok(())
+    }
+
+    fn remove_work(&mut self, hash: &BlockHash) -> Result<(), StorageError> {
+        self.dirty_work.insert(*hash, None);
+        Ok(())
+    }
+
+    fn active_hash(&self, height: u64) -> Result<Option<BlockHash>, StorageError> {
+        if let Some(hash) = self.dirty_active.get(&height) {
+            return Ok(*hash);
+        }
+
+        match self.index.active.get(&height) {
+            Some(offset) => match self.state.read_at(*offset)? {
+                StateRecord::Active(_, hash) => Ok(Some(hash)),
+                _ => Err(StorageError::CorruptRecord(*offset)),
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn put_active_hash(&mut self, height: u64, hash: &BlockHash) -> Result<(), StorageError> {
+        self.dirty_active.insert(height, Some(*hash));
+        Ok(())
+    }
+
+    fn remove_active_hash(&mut self, height: u64) -> Result<(), StorageError> {
+        self.dirty_active.insert(height, None);
+        Ok(())
+    }
+
+    fn base(&self) -> Result<Option<u64>, StorageError> {
+        Ok(self.dirty_base.or(self.index.base))
+    }
+
+    fn put_base(&mut self, base: u64) -> Result<(), StorageError> {
+        self.dirty_base = Some(base);
+        Ok(())
+    }
+
+    fn transaction(&self, id: &TransactionId) -> Result<Option<(u64, usize)>, StorageError> {
+        if let Some(location) = self.dirty_transactions.get(id) {
+            return Ok(*location);
+        }
+
+        match self.index.transactions.get(id) {
+            Some(offset) => match self.state.read_at(*offset)? {
+                StateRecord::Transaction(_, height, position) => Ok(Some((height, position))),
+                _ => Err(StorageError::CorruptRecord(*offset)),
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn put_transaction(
+        &mut self,
+        id: &TransactionId,
+        height: u64,
+        position: usize,
+    ) -> Result<(), StorageError> {
+        self.dirty_transactions
+            .insert(*id, Some((height, position)));
+        Ok(())
+    }
+
+    fn remove_transaction(&mut self, id: &TransactionId) -> Result<(), StorageError> {
+        self.dirty_transactions.insert(*id, None);
+        Ok(())
+    }
+
+    fn undo(&self, hash: &BlockHash) -> Result<Option<StateUndo>, StorageError> {
+        if let Some(undo) = self.dirty_undo.get(hash) {
+            return Ok(undo.clone());
+        }
+
+        match self.index.undo.get(hash) {
+            Some(offset) => match self.state.read_at(*offset)? {
+                StateRecord::Undo(_, undo) => Ok(Some(undo)),
+                _ => Err(StorageError::CorruptRecord(*offset)),
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn put_undo(&mut self, hash: &BlockHash, undo: StateUndo) -> Result<(), StorageError> {
+        self.dirty_undo.insert(*hash, Some(undo));
+        Ok(())
+    }
+
+    fn remove_undo(&mut self, hash: &BlockHash) -> Result<(), StorageError> {
+        self.dirty_undo.insert(*hash, None);
+        Ok(())
+    }
+
+    fn params(&self) -> Result<Option<ConsensusParams>, StorageError> {
+        if let Some(params) = self.dirty_params {
+            return Ok(Some(params));
+        }
+
+        match self.index.params {
+            Some(offset) => match self.state.read_at(offset)? {
+                StateRecord::Params(params) => Ok(Some(params)),
+                _ => Err(StorageError::CorruptRecord(offset)),
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn put_params(&mut self, params: ConsensusParams) -> Result<(), StorageError> {
+        self.dirty_params = Some(params);
+        Ok(())
+    }
+
+    fn commit(&mut self, tip: &BlockHash) -> Result<(), StorageError> {
+        let clean = self.dirty_accounts.is_empty()
+            && self.dirty_nodes.is_empty()
+            && self.dirty_undo.is_empty()
+            && self.dirty_blocks.is_empty()
+            && self.dirty_work.is_empty()
+            && self.dirty_active.is_empty()
+            && self.dirty_transactions.is_empty()
+            && self.dirty_params.is_none()
+            && self.dirty_base.is_none();
+        if clean && self.index.tip == Some(*tip) {
+            return Ok(());
+        }
+
+        let mut records = Vec::new();
+        for (address, account) in self.dirty_accounts.drain() {
+            records.push(match account {
+                Some(account) => StateRecord::Account(Box::new(account)),
+                None => StateRecord::AccountRemoved(address),
+            });
+        }
+        for (key, node) in self.dirty_nodes.drain() {
+            records.push(match node {
+                Some(node) => StateRecord::Node(key, node),
+                None => StateRecord::NodeRemoved(key),
+            });
+        }
+        for (hash, undo) in self.dirty_undo.drain() {
+            records.push(match undo {
+                Some(undo) => StateRecord::Undo(hash, undo),
+                None => StateRecord::UndoRemoved(hash),
+            });
+        }
+        for (hash, work) in self.dirty_work.drain() {
+            records.push(match work {
+                Some(work) => StateRecord::Work(hash, work),
+                None => StateRecord::WorkRemoved(hash),
+            });
+        }
+        for (height, hash) in self.dirty_active.drain() {
+            records.push(match hash {
+                Some(hash) => StateRecord::Active(height, hash),
+                None => StateRecord::ActiveRemoved(height),
+            });
+        }
+        for (id, location) in self.dirty_transactions.drain() {
+            records.push(match location {
+                Some((height, position)) => StateRecord::Transaction(id, height, position),
+                None => StateRecord::TransactionRemoved(id),
+            });
+        }
+        records.extend(self.dirty_blocks.drain(..).map(StateRecord::BlockRemoved));
+        records.extend(self.dirty_params.take().map(StateRecord::Params));
+        records.extend(self.dirty_base.take().map(StateRecord::Base));
+        records.push(StateRecord::Commit(*tip));
+        write_batch(&mut self.state, &mut self.index, &records)?;
+
+        self.since_snapshot += 1;
+        if self.since_snapshot >= self.snapshot_interval {
+            self.snapshot()?;
+        }
+
+        Ok(())
+    }
+
+    fn committed_tip(&self) -> Option<BlockHash> {
+        self.index.tip
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    use crate::ledger::testing::{self, Key};
+    use crate::ledger::{ConsensusParams, Ledger, LedgerError};
+
+    use super::*;
+
+    /// A funded key and a chain of `blocks` blocks on `storage`, each sending
+    /// some of its funds.
+    fn chain(storage: FileStorage, blocks: u64) -> (Ledger<FileStorage>, Key) {
+        let a = Key::new();
+        let genesis = testing::genesis(&[(a.address, 1_000)]);
+        let mut ledger = Ledger::open(storage, genesis, ConsensusParams::default()).unwrap();
+        for nonce in 0..blocks {
+            let t = a.transfer(Address::from([7u8; 32]), 10, 1, nonce);
+            testing::mine(&mut ledger, Address::from([9u8; 32]), vec![t]).unwrap();
+        }
+        (ledger, a)
+    }
+
+    fn reopen(ledger: Ledger<FileStorage>) -> Ledger<FileStorage> {
+        let dir = ledger.storage().dir().to_path_buf();
+        let genesis = ledger.genesis_config().clone();
+        let params = *ledger.params();
+        drop(ledger);
+
+        Ledger::open(FileStorage::open(dir).unwrap(), genesis, params).unwrap()
+    }
+
+    fn append_garbage(path: &Path, bytes: &[u8]) {
+        let mut file = OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(bytes).unwrap();
+    }
+
+    #[test]
+    fn reopen_resumes_at_tip() {
+        let dir = tempfile::tempdir().unwrap();
+        let (ledger, a) = chain(FileStorage::open(dir.path()).unwrap(), 3);
+        let tip = ledger.last().unwrap();
+        let root = ledger.state_root().unwrap();
+
+        let ledger = reopen(ledger);
+        assert_eq!(ledger.storage().truncated(), 0);
+        assert_eq!(ledger.height(), 3);
+        assert_eq!(ledger.last().unwrap(), tip);
+        assert_eq!(ledger.state_root().unwrap(), root);
+        assert_eq!(ledger.balance(a.address).unwrap(), 1_000 - 3 * 11);
+        assert_eq!(ledger.nonce(a.address).unwrap(), 3);
+    }
+
+    #[test]
+    fn reopen_keeps_side_branches_and_transaction_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut ledger, a) = chain(FileStorage::open(dir.path()).unwrap(), 0);
+        let mut fork =
+            Ledger::from_genesis(ledger.genesis_config().clone(), *ledger.params()).unwrap();
+        let kept = a.transfer(Address::from([7u8; 32]), 10, 1, 0);
+        let detached = a.transfer(Address::from([7u8; 32]), 10, 1, 1);
+        testing::mine(&mut ledger, Address::from([9u8; 32]), vec![kept, detached]).unwrap();
+        let side = testing::mine(&mut fork, Address::from([8u8; 32]), vec![kept]).unwrap();
+        ledger.append_block(side.clone()).unwrap();
+        let work = ledger.work().unwrap();
+
+        let mut ledger = reopen(ledger);
+        assert_eq!(ledger.height(), 1);
+        assert_eq!(ledger.work().unwrap(), work);
+        assert_eq!(
+            ledger.find_transaction(&detached.id()).unwrap().unwrap().1,
+            2
+        );
+        assert!(ledger.block(side.hash()).unwrap().is_some());
+
+        let block = testing::mine(&mut fork, Address::from([8u8; 32]), vec![]).unwrap();
+        ledger.append_block(block.clone()).unwrap();
+        assert_eq!(ledger.pending(), [detached]);
+        let ledger = reopen(ledger);
+        assert_eq!(ledger.last().unwrap(), block);
+        assert_eq!(ledger.block_at(1).unwrap().unwrap(), side);
+        assert_eq!(ledger.find_transaction(&kept.id()).unwrap().unwrap().1, 1);
+        assert!(ledger.find_transaction(&detached.id()).unwrap().is_none());
+    }
+
+    #[test]
+    fn reopen_checks_consensus_params() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = FileStorage::open(dir.path())
+            .unwrap()
+            .with_snapshot_interval(2);
+        let (ledger, _) = chain(storage, 3);
+        let genesis = ledger.genesis_config().clone();
+        drop(ledger);
+
+        let params = ConsensusParams {
+            min_fee: 2,
+            ..ConsensusParams::default()
+        };
+        let e = Ledger::open(
+            FileStorage::open(dir.path()).unwrap(),
+            genesis.clone(),
+            params,
+        )
+        .unwrap_err();
+        assert!(matches!(e, LedgerError::ParamsMismatch), "{e:?}");
+
+        let ledger = Ledger::open(
+            FileStorage::open(dir.path()).unwrap(),
+            genesis,
+            ConsensusParams::default(),
+        )
+        .unwrap();
+        assert_eq!(ledger.height(), 3);
+        assert_eq!(
+            ledger.storage().params().unwrap(),
+            Some(ConsensusParams::default())
+        );
+    }
+
+    #[test]
+    fn torn_tails_are_truncated() {
+        let dir = tempfile::tempdir().unwrap();
+        let (ledger, _) = chain(FileStorage::open(dir.path()).unwrap(), 2);
+        let tip = ledger.last().unwrap();
+
+        // A length and checksum promising more payload than follows.
+        append_garbage(&dir.path().join(BLOCKS_FILE), &[0, 0, 1, 0, 1, 2, 3, 4, 5]);
+        append_garbage(&dir.path().join(STATE_FILE), &[0, 0, 0, 9, 1, 2]);
+
+        let mut ledger = reopen(ledger);
+        assert_eq!(ledger.storage().truncated(), 9 + 6);
+        assert_eq!(ledger.last().unwrap(), tip);
+
+        testing::mine(&mut ledger, Address::from([9u8; 32]), vec![]).unwrap();
+        let ledger = reopen(ledger);
+        assert_eq!(ledger.storage().truncated(), 0);
+        assert_eq!(ledger.height(), 3);
+    }
+
+    /// Flips a byte `at` bytes into the file at `path`.
+    fn flip_byte(path: &Path, at: usize) {
+        let mut bytes = fs::read(path).unwrap();
+        bytes[at] ^= 0xff;
+        fs::write(path, bytes).unwrap();
+    }
+
+    #[test]
+    fn corrupt_last_record_is_a_torn_tail() {
+        let dir = tempfile::tempdir().unwrap();
+        let (ledger, _) = chain(FileStorage::open(dir.path()).unwrap(), 2);
+        let hashes = ledger.storage().block_hashes().unwrap();
+        drop(ledger);
+
+        let path = dir.path().join(BLOCKS_FILE);
+        let len = fs::metadata(&path).unwrap().len();
+        flip_byte(&path, len as usize - 1);
+
+        let blocks = BlockLog::open(&path).unwrap();
+        assert_eq!(blocks.hashes(), &hashes[..2]);
+        assert!(blocks.truncated() > 0);
+        assert_eq!(fs::metadata(&path).unwrap().len(), len - blocks.truncated());
+    }
+
+    #[test]
+    fn corruption_before_the_tail_is_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let (ledger, _) = chain(FileStorage::open(dir.path()).unwrap(), 2);
+        drop(ledger);
+
+        let path = dir.path().join(BLOCKS_FILE);
+        let len = fs::metadata(&path).unwrap().len();
+        // Inside the payload of the first block, past its length and checksum.
+        flip_byte(&path, 9);
+
+        assert!(matches!(
+            BlockLog::open(&path),
+            Err(StorageError::CorruptRecord(0))
+        ));
+        assert!(matches!(
+            BlockLog::read(&path).unwrap().next(),
+            Some(Err(StorageError::CorruptRecord(0)))
+        ));
+        assert!(matches!(
+            FileStorage::open(dir.path()),
+            Err(StorageError::CorruptRecord(0))
+        ));
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+    }
+
+    #[test]
+    fn reading_a_block_log_leaves_it_untouched() {
+        let dir = tempfile::tempdir().unwrap();
+        let (ledger, _) = chain(FileStorage::open(dir.path()).unwrap(), 2);
+        let path = dir.path().join(BLOCKS_FILE);
+        append_garbage(&path, &[0, 0, 1, 0, 1, 2, 3, 4, 5]);
+        let len = fs::metadata(&path).unwrap().len();
+
+        let blocks: Vec<_> = BlockLog::read(&path)
+            .unwrap()
+            .map(|block| *block.unwrap().hash())
+            .collect();
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(blocks.last(), Some(ledger.last().unwrap().hash()));
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+    }
+
+    #[test]
+    fn uncommitted_batch_is_rolled_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let address = Address::from([1u8; 32]);
+        let tip = BlockHash::from([2u8; 32]);
+        let mut committed = Account::new(address);
+        committed.balance = 10;
+
+        let mut storage = FileStorage::open(dir.path()).unwrap();
+        storage.put_account(committed).unwrap();
+        storage.commit(&tip).unwrap();
+
+        // Records of a batch whose commit never made it to disk.
+        let mut changed = committed;
+        changed.balance = 20;
+        let start = storage.state.len();
+        storage
+            .state
+            .append(&[
+                StateRecord::Account(Box::new(changed)),
+                StateRecord::AccountRemoved(Address::from([3u8; 32])),
+            ])
+            .unwrap();
+        let written = storage.state.len() - start;
+
+        // Buffered writes that were never committed are lost as well.
+        storage
+            .put_account(Account::new(Address::from([4u8; 32])))
+            .unwrap();
+        drop(storage);
+
+        let storage = FileStorage::open(dir.path()).unwrap();
+        assert_eq!(storage.truncated(), written);
+        assert_eq!(storage.committed_tip(), Some(tip));
+        assert_eq!(storage.account(&address).unwrap(), Some(committed));
+        assert_eq!(storage.accounts().unwrap(), vec![committed]);
+    }
+
+    #[test]
+    fn compaction_keeps_live_records_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = FileStorage::open(dir.path().join("compacted"))
+            .unwrap()
+            .with_snapshot_interval(4);
+        let (compacted, a) = chain(storage, 8);
+        let (uncompacted, _) = chain(FileStorage::open(dir.path().join("full")).unwrap(), 8);
+
+        let state_len = |ledger: &Ledger<FileStorage>| {
+            fs::metadata(ledger.storage().dir().join(STATE_FILE))
+                .unwrap()
+                .len()
+        };
+        assert!(state_len(&compacted) < state_len(&uncompacted));
+        assert!(!dir.path().join("compacted").join(STATE_TMP_FILE).exists());
+
+        let tip = compacted.last().unwrap();
+        let root = compacted.state_root().unwrap();
+        let compacted = reopen(compacted);
+        assert_eq!(compacted.storage().truncated(), 0);
+        assert_eq!(compacted.last().unwrap(), tip);
+        assert_eq!(compacted.state_root().unwrap(), root);
+        assert_eq!(compacted.balance(a.address).unwrap(), 1_000 - 8 * 11);
+    }
+}
@@ -0,0 +1,398 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+
+use bincode::{Decode, Encode, config};
+use sha3::{Digest, Sha3_256};
+
+use crate::block::{Block, BlockHash};
+
+use super::error::StorageError;
+
+/// Size of the length and checksum preceding every record.
+const RECORD_HEADER_LEN: u64 = 8;
+
+/// Longest payload accepted when reading, so that a corrupt length cannot
+/// trigger a huge allocation.
+const MAX_RECORD_LEN: usize = 64 * 1024 * 1024;
+
+/// Append-only file of bincode encoded records.
+///
+/// Each record is a big-endian `u32` payload length, the first four bytes of
+/// the SHA3-256 of the payload, then the payload. A record cut short by a
+/// crash ends the log and is truncated when the log is opened, as is a last
+/// record that fails its checksum. A bad record followed by more data was not
+/// torn by a crash, so opening the log fails instead of dropping what follows.
+#[derive(Debug)]
+pub(super) struct RecordLog<T> {
+    path: PathBuf,
+    file: File,
+    len: u64,
+    truncated: u64,
+    records: PhantomData<T>,
+}
+
+impl<T: Encode + Decode<()>> RecordLog<T> {
+    /// Opens the log at `path`, creating it if needed, and passes every valid
+    /// record to `visit` along with its offset.
+    pub(super) fn open(
+        path: impl AsRef<Path>,
+        mut visit: impl FnMut(u64, T),
+    ) -> Result<Self, StorageError> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&path)?;
+        sync_parent(&path)?;
+
+        let file_len = file.metadata()?.len();
+        let mut reader = BufReader::new(File::open(&path)?);
+        let mut len = 0;
+        while let Some((record, record_len)) = read_record(&mut reader, len, file_len)? {
+            visit(len, record);
+            len += record_len;
+        }
+
+        let mut log = Self {
+            path,
+            file,
+            len: file_len,
+            truncated: 0,
+            records: PhantomData,
+        };
+        if len < file_len {
+            log.truncate(len)?;
+            log.truncated = file_len - len;
+        }
+
+        Ok(log)
+    }
+
+    /// Bytes dropped from the end of the log when it was opened.
+    pub(super) fn truncated(&self) -> u64 {
+        self.truncated
+    }
+
+    /// Size of the log in bytes, which is also the offset of the next record.
+    pub(super) fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Appends `records` with a single write and waits until they are on
+    /// disk. Returns the offset of each record.
+    pub(super) fn append(&mut self, records: &[T]) -> Result<Vec<u64>, StorageError> {
+        let mut buf = Vec::new();
+        let mut offsets = Vec::with_capacity(records.len());
+
+        for record in records {
+            let payload = bincode::encode_to_vec(record, config::standard())?;
+            let length = u32::try_from(payload.len())
+                .ok()
+                .filter(|length| *length as usize <= MAX_RECORD_LEN)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "record too large"))?;
+
+            offsets.push(self.len + buf.len() as u64);
+            buf.extend(length.to_be_bytes());
+            buf.extend(checksum(&payload));
+            buf.extend(&payload);
+        }
+
+        self.file.write_all(&buf)?;
+        self.file.sync_data()?;
+        self.len += buf.len() as u64;
+
+        Ok(offsets)
+    }
+
+    pub(super) fn read_at(&self, offset: u64) -> Result<T, StorageError> {
+        read_indexed(&mut File::open(&self.path)?, offset)
+    }
+
+    /// Reads the records at `offsets` through a single handle on the log.
+    pub(super) fn read_each(
+        &self,
+        offsets: impl IntoIterator<Item = u64>,
+    ) -> Result<impl Iterator<Item = Result<T, StorageError>>, StorageError> {
+        let mut file = File::open(&self.path)?;
+        Ok(offsets
+            .into_iter()
+            .map(move |offset| read_indexed(&mut file, offset)))
+    }
+
+    /// Moves the log to `path`, replacing any file already there.
+    pub(super) fn rename(&mut self, path: impl AsRef<Path>) -> Result<(), StorageError> {
+        let path = path.as_ref().to_path_buf();
+        fs::rename(&self.path, &path)?;
+        sync_parent(&path)?;
+        self.path = path;
+
+        Ok(())
+    }
+
+    /// Drops everything from `len` onwards.
+    pub(super) fn truncate(&mut self, len: u64) -> Result<(), StorageError> {
+        self.file.set_len(len)?;
+        self.file.sync_all()?;
+        self.len = len;
+
+        Ok(())
+    }
+}
+
+/// Records of a log read front to back without opening it for writing.
+///
+/// Iteration stops at a torn tail, which is left in place: it may be a write
+/// still in progress. Only the records present when the reader was opened
+/// are read.
+pub(super) struct RecordReader<T> {
+    reader: BufReader<File>,
+    offset: u64,
+    len: u64,
+    done: bool,
+    records: PhantomData<T>,
+}
+
+impl<T: Decode<()>> RecordReader<T> {
+    pub(super) fn open(path: impl AsRef<Path>) -> Result<Self, StorageError> {
+        let file = File::open(path)?;
+        Ok(Self {
+            len: file.metadata()?.len(),
+            reader: BufReader::new(file),
+            offset: 0,
+            done: false,
+            records: PhantomData,
+        })
+    }
+}
+
+impl<T: Decode<()>> Iterator for RecordReader<T> {
+    type Item = Result<T, StorageError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        match read_record(&mut self.reader, self.offset, self.len) {
+            Ok(Some((record, record_len))) => {
+                self.offset += record_len;
+                Some(Ok(record))
+            }
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Append-only file of blocks, indexed by hash and height.
+///
+/// Every append is flushed to disk before it returns. The index is kept in
+/// memory and rebuilt by scanning the log on open.
+#[derive(Debug)]
+pub struct BlockLog {
+    log: RecordLog<Block>,
+    order: Vec<BlockHash>,
+    by_hash: HashMap<BlockHash, u64>,
+    by_height: BTreeMap<u64, Vec<u64>>,
+}
+
+impl BlockLog {
+    /// Opens the log at `path`, creating it if needed, and recovers from a
+    /// torn tail.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, StorageError> {
+        let mut order = Vec::new();
+        let mut by_hash = HashMap::new();
+        let mut by_height: BTreeMap<u64, Vec<u64>> = BTreeMap::new();
+
+        let log = RecordLog::open(path, |offset, block: Block| {
+            if by_hash.insert(*block.hash(), offset).is_none() {
+                order.push(*block.hash());
+                by_height.entry(block.index()).or_default().push(offset);
+            }
+        })?;
+
+        Ok(Self {
+            log,
+            order,
+            by_hash,
+            by_height,
+        })
+    }
+
+    /// Blocks of the log at `path` in the order they were appended, read
+    /// without modifying it, so the log may belong to a running node.
+    pub fn read(
+        path: impl AsRef<Path>,
+    ) -> Result<impl Iterator<Item = Result<Block, StorageError>>, StorageError> {
+        RecordReader::open(path)
+    }
+
+    /// Bytes dropped from the end of the log when it was opened.
+    pub fn truncated(&self) -> u64 {
+        self.log.truncated()
+    }
+
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+
+    pub fn contains(&self, hash: &BlockHash) -> bool {
+        self.by_hash.contains_key(hash)
+    }
+
+    /// Appends `block` and waits until it is on disk. Blocks already in the
+    /// log are not written again.
+    pub fn append(&mut self, block: &Block) -> Result<(), StorageError> {
+        if self.contains(block.hash()) {
+            return Ok(());
+        }
+
+        let offsets = self.log.append(std::slice::from_ref(block))?;
+        self.order.push(*block.hash());
+        self.by_hash.insert(*block.hash(), offsets[0]);
+        self.by_height
+            .entry(block.index())
+            .or_default()
+            .push(offsets[0]);
+
+        Ok(())
+    }
+
+    pub fn get(&self, hash: &BlockHash) -> Result<Option<Block>, StorageError> {
+        self.by_hash
+            .get(hash)
+            .map(|offset| self.log.read_at(*offset))
+            .transpose()
+    }
+
+    /// Every stored block at `height`, on the active chain or not.
+    pub fn at_height(&self, height: u64) -> Result<Vec<Block>, StorageError> {
+        self.by_height
+            .get(&height)
+            .into_iter()
+            .flatten()
+            .map(|offset| self.log.read_at(*offset))
+            .collect()
+    }
+
+    /// Hashes of every stored block, in the order they were appended.
+    pub fn hashes(&self) -> &[BlockHash] {
+        &self.order
+    }
+
+    /// Every stored block, in the order they were appended.
+    pub fn blocks(&self) -> impl Iterator<Item = Result<Block, StorageError>> + '_ {
+        self.order
+            .iter()
+            .map(|hash| self.log.read_at(self.by_hash[hash]))
+    }
+
+    /// Drops `hash` from the index. The record stays in the file, so callers
+    /// must remember the removal themselves to survive a restart.
+    pub(super) fn forget(&mut self, hash: &BlockHash) {
+        if let Some(offset) = self.by_hash.remove(hash) {
+            self.order.retain(|h| h != hash);
+            for offsets in self.by_height.values_mut() {
+                offsets.retain(|o| *o != offset);
+            }
+        }
+    }
+}
+
+/// Reads the record at `offset`, the current position of `reader`, in a log
+/// of `file_len` bytes. Returns it with its length, or `None` at the end of the
+/// log or at a torn tail: a record running past the end of the file, or a
+/// last record that fails its checksum or does not decode.
+///
+/// A bad record with data after it cannot have been torn by a crash and is
+/// reported as [`StorageError::CorruptRecord`].
+fn read_record<T: Decode<()>>(
+    reader: &mut impl Read,
+    offset: u64,
+    file_len: u64,
+) -> Result<Option<(T, u64)>, StorageError> {
+    let mut header = [0u8; RECORD_HEADER_LEN as usize];
+    if !read_full(reader, &mut header)? {
+        return Ok(None);
+    }
+
+    let length = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+    let end = offset + RECORD_HEADER_LEN + length as u64;
+    if end > file_len {
+        return Ok(None);
+    }
+    if length > MAX_RECORD_LEN {
+        return Err(StorageError::CorruptRecord(offset));
+    }
+
+    let mut payload = vec![0u8; length];
+    if !read_full(reader, &mut payload)? {
+        return Ok(None);
+    }
+
+    let record = (checksum(&payload) == header[4..])
+        .then(|| bincode::decode_from_slice::<T, _>(&payload, config::standard()).ok())
+        .flatten();
+    match record {
+        Some((record, _)) => Ok(Some((record, RECORD_HEADER_LEN + length as u64))),
+        None if end == file_len => Ok(None),
+        None => Err(StorageError::CorruptRecord(offset)),
+    }
+}
+
+/// Reads the record at `offset` of `file`, which an index points to and so
+/// must be valid.
+fn read_indexed<T: Decode<()>>(file: &mut File, offset: u64) -> Result<T, StorageError> {
+    file.seek(SeekFrom::Start(offset))?;
+
+    let (record, _) =
+        read_record(file, offset, u64::MAX)?.ok_or(StorageError::CorruptRecord(offset))?;
+    Ok(record)
+}
+
+/// Fills `buf`, returning false if the input ends first.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> Result<bool, StorageError> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn checksum(payload: &[u8]) -> [u8; 4] {
+    let digest = Sha3_256::digest(payload);
+    [digest[0], digest[1], digest[2], digest[3]]
+}
+
+/// Flushes the directory entry of `path` to disk.
+pub(super) fn sync_parent(path: &Path) -> Result<(), StorageError> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        File::open(dir)?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+/// Creates `dir` and its parents, making the new entries durable.
+pub(super) fn create_dir(dir: &Path) -> Result<(), StorageError> {
+    if !dir.exists() {
+        fs::create_dir_all(dir)?;
+        sync_parent(dir)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+
+use bincode::{Decode, Encode};
+
+use crate::account::{Account, Address, NodeKey, StateNode};
+use crate::block::{Block, BlockHash};
+use crate::ledger::ConsensusParams;
+use crate::transaction::TransactionId;
+
+use super::error::StorageError;
+use super::storage::{StateUndo, Storage};
+
+/// Storage kept entirely in memory, lost when dropped.
+#[derive(Debug, Clone, Default, Encode, Decode)]
+pub struct MemoryStorage {
+    accounts: HashMap<Address, Account>,
+    nodes: HashMap<NodeKey, StateNode>,
+    blocks: HashMap<BlockHash, Block>,
+    order: Vec<BlockHash>,
+    work: HashMap<BlockHash, u128>,
+    active: HashMap<u64, BlockHash>,
+    base: Option<u64>,
+    transactions: HashMap<TransactionId, (u64, usize)>,
+    undo: HashMap<BlockHash, StateUndo>,
+    params: Option<ConsensusParams>,
+    tip: Option<BlockHash>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn account(&self, address: &Address) -> Result<Option<Account>, StorageError> {
+        Ok(self.accounts.get(address).copied())
+    }
+
+    fn put_account(&mut self, account: Account) -> Result<(), StorageError> {
+        self.accounts.insert(account.address(), account);
+        Ok(())
+    }
+
+    fn remove_account(&mut self, address: &Address) -> Result<(), StorageError> {
+        self.accounts.remove(address);
+        Ok(())
+    }
+
+    fn accounts(&self) -> Result<Vec<Account>, StorageError> {
+        Ok(self.accounts.values().copied().collect())
+    }
+
+    fn node(&self, key: &NodeKey) -> Result<Option<StateNode>, StorageError> {
+        Ok(self.nodes.get(key).copied())
+    }
+
+    fn put_node(&mut self, key: NodeKey, node: StateNode) -> Result<(), StorageError> {
+        self.nodes.insert(key, node);
+        Ok(())
+    }
+
+    fn remove_node(&mut self, key: &NodeKey) -> Result<(), StorageError> {
+        self.nodes.remove(key);
+        Ok(())
+    }
+
+    fn block(&self, hash: &BlockHash) -> Result<Option<Block>, StorageError> {
+        Ok(self.blocks.get(hash).cloned())
+    }
+
+    fn put_block(&mut self, block: &Block) -> Result<(), StorageError> {
+        if self.blocks.insert(*block.hash(), block.clone()).is_none() {
+            self.order.push(*block.hash());
+        }
+        Ok(())
+    }
+
+    fn remove_block(&mut self, hash: &BlockHash) -> Result<(), StorageError> {
+        if self.blocks.remove(hash).is_some() {
+            self.order.retain(|h| h != hash);
+        }
+        Ok(())
+    }
+
+    fn block_hashes(&self) -> Result<Vec<BlockHash>, StorageError> {
+        Ok(self.order.clone())
+    }
+
+    fn work(&self, hash: &BlockHash) -> Result<Option<u128>, StorageError> {
+        Ok(self.work.get(hash).copied())
+    }
+
+    fn put_work(&mut self, hash: &BlockHash, work: u128) -> Result<(), StorageError> {
+        self.work.insert(*hash, work);
+        Ok(())
+    }
+
+    fn remove_work(&mut self, hash: &BlockHash) -> Result<(), StorageError> {
+        self.work.remove(hash);
+        Ok(())
+    }
+
+    fn active_hash(&self, height: u64) -> Result<Option<BlockHash>, StorageError> {
+        Ok(self.active.get(&height).copied())
+    }
+
+    fn put_active_hash(&mut self, height: u64, hash: &BlockHash) -> Result<(), StorageError> {
+        self.active.insert(height, *hash);
+        Ok(())
+    }
+
+    fn remove_active_hash(&mut self, height: u64) -> Result<(), StorageError> {
+        self.active.remove(&height);
+        Ok(())
+    }
+
+    fn base(&self) -> Result<Option<u64>, StorageError> {
+        Ok(self.base)
+    }
+
+    fn put_base(&mut self, base: u64) -> Result<(), StorageError> {
+        self.base = Some(base);
+        Ok(())
+    }
+
+    fn transaction(&self, id: &TransactionId) -> Result<Option<(u64, usize)>, StorageError> {
+        Ok(self.transactions.get(id).copied())
+    }
+
+    fn put_transaction(
+        &mut self,
+        id: &TransactionId,
+        height: u64,
+        position: usize,
+    ) -> Result<(), StorageError> {
+        self.transactions.insert(*id, (height, position));
+        Ok(())
+    }
+
+    fn remove_transaction(&mut self, id: &TransactionId) -> Result<(), StorageError> {
+        self.transactions.remove(id);
+        Ok(())
+    }
+
+    fn undo(&self, hash: &BlockHash) -> Result<Option<StateUndo>, StorageError> {
+        Ok(self.undo.get(hash).cloned())
+    }
+
+    fn put_undo(&mut self, hash: &BlockHash, undo: StateUndo) -> Result<(), StorageError> {
+        self.undo.insert(*hash, undo);
+        Ok(())
+    }
+
+    fn remove_undo(&mut self, hash: &BlockHash) -> Result<(), StorageError> {
+        self.undo.remove(hash);
+        Ok(())
+    }
+
+    fn params(&self) -> Result<Option<ConsensusParams>, StorageError> {
+        Ok(self.params)
+    }
+
+    fn put_params(&mut self, params: ConsensusParams) -> Result<(), StorageError> {
+        self.params = Some(params);
+        Ok(())
+    }
+
+    fn commit(&mut self, tip: &BlockHash) -> Result<(), StorageError> {
+        self.tip = Some(*tip);
+        Ok(())
+    }
+
+    fn committed_tip(&self) -> Option<BlockHash> {
+        self.tip
+    }
+}
@@ -0,0 +1,11 @@
+mod error;
+mod file;
+mod log;
+mod memory;
+mod storage;
+
+pub use error::StorageError;
+pub use file::{FileStorage, SNAPSHOT_INTERVAL};
+pub use log::BlockLog;
+pub use memory::MemoryStorage;
+pub use storage::{StateUndo, Storage};
@@ -0,0 +1,185 @@
+use crate::account::{Account, Address, NodeKey, StateNode};
+use crate::block::{Block, BlockHash};
+use crate::ledger::ConsensusParams;
+use crate::transaction::TransactionId;
+
+use super::error::StorageError;
+
+/// Previous records of the accounts touched by a block, used to roll state back.
+pub type StateUndo = Vec<(Address, Option<Account>)>;
+
+/// Blocks and the work behind them, the active chain and its transactions,
+/// account state, the nodes of its state tree, undo records and the consensus
+/// parameters behind a [`Ledger`](crate::ledger::Ledger).
+///
+/// Writes may be buffered until [`Storage::commit`], which the ledger calls
+/// whenever state matches the tip of its active chain. Storage that outlives
+/// the process must make everything written before a commit durable at once,
+/// so that the ledger can be reopened at that tip.
+pub trait Storage {
+    fn account(&self, address: &Address) -> Result<Option<Account>, StorageError>;
+    fn put_account(&mut self, account: Account) -> Result<(), StorageError>;
+    fn remove_account(&mut self, address: &Address) -> Result<(), StorageError>;
+    /// Every stored account, in no particular order.
+    fn accounts(&self) -> Result<Vec<Account>, StorageError>;
+
+    /// Node of the [`StateTree`](crate::account::StateTree) over the stored
+    /// accounts.
+    fn node(&self, key: &NodeKey) -> Result<Option<StateNode>, StorageError>;
+    fn put_node(&mut self, key: NodeKey, node: StateNode) -> Result<(), StorageError>;
+    fn remove_node(&mut self, key: &NodeKey) -> Result<(), StorageError>;
+
+    fn block(&self, hash: &BlockHash) -> Result<Option<Block>, StorageError>;
+    fn put_block(&mut self, block: &Block) -> Result<(), StorageError>;
+    fn remove_block(&mut self, hash: &BlockHash) -> Result<(), StorageError>;
+    /// Hashes of every stored block, parents before their children.
+    fn block_hashes(&self) -> Result<Vec<BlockHash>, StorageError>;
+
+    /// Cumulative work of the branch ending at a block of the block tree. A
+    /// stored block without it is not part of the tree.
+    fn work(&self, hash: &BlockHash) -> Result<Option<u128>, StorageError>;
+    fn put_work(&mut self, hash: &BlockHash, work: u128) -> Result<(), StorageError>;
+    fn remove_work(&mut self, hash: &BlockHash) -> Result<(), StorageError>;
+
+    /// Hash of the block at `height` of the active chain.
+    fn active_hash(&self, height: u64) -> Result<Option<BlockHash>, StorageError>;
+    fn put_active_hash(&mut self, height: u64, hash: &BlockHash) -> Result<(), StorageError>;
+    fn remove_active_hash(&mut self, height: u64) -> Result<(), StorageError>;
+    /// Height of the first block of the active chain.
+    fn base(&self) -> Result<Option<u64>, StorageError>;
+    fn put_base(&mut self, base: u64) -> Result<(), StorageError>;
+
+    /// Height of the active block including a transaction, and its position
+    /// in the block.
+    fn transaction(&self, id: &TransactionId) -> Result<Option<(u64, usize)>, StorageError>;
+    fn put_transaction(
+        &mut self,
+        id: &TransactionId,
+        height: u64,
+        position: usize,
+    ) -> Result<(), StorageError>;
+    fn remove_transaction(&mut self, id: &TransactionId) -> Result<(), StorageError>;
+
+    /// Undo record of a block on the active chain.
+    fn undo(&self, hash: &BlockHash) -> Result<Option<StateUndo>, StorageError>;
+    fn put_undo(&mut self, hash: &BlockHash, undo: StateUndo) -> Result<(), StorageError>;
+    fn remove_undo(&mut self, hash: &BlockHash) -> Result<(), StorageError>;
+
+    /// Consensus parameters the stored chain was validated under.
+    fn params(&self) -> Result<Option<ConsensusParams>, StorageError>;
+    fn put_params(&mut self, params: ConsensusParams) -> Result<(), StorageError>;
+
+    /// Marks everything written so far as the state at `tip`.
+    fn commit(&mut self, tip: &BlockHash) -> Result<(), StorageError>;
+    /// Tip of the last commit, or `None` if nothing was ever committed.
+    fn committed_tip(&self) -> Option<BlockHash>;
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block::Block;
+    use crate::storage::{FileStorage, MemoryStorage};
+    use crate::transaction::Transaction;
+
+    use super::*;
+
+    fn block(index: u64, previous_hash: BlockHash) -> Block {
+        let coinbase = Transaction::mint(Address::from([1u8; 32]), 50, index);
+        Block::forge(index, 1, previous_hash, vec![coinbase], BlockHash::ZERO, 1).unwrap()
+    }
+
+    /// Behaviour every backend must share.
+    fn exercise(mut storage: impl Storage) {
+        let a = Address::from([1u8; 32]);
+        let b = Address::from([2u8; 32]);
+        assert_eq!(storage.committed_tip(), None);
+        assert_eq!(storage.account(&a).unwrap(), None);
+
+        let mut account = Account::new(a);
+        account.balance = 10;
+        storage.put_account(account).unwrap();
+        storage.put_account(Account::new(b)).unwrap();
+        storage.remove_account(&b).unwrap();
+        assert_eq!(storage.account(&a).unwrap(), Some(account));
+        assert_eq!(storage.account(&b).unwrap(), None);
+        assert_eq!(storage.accounts().unwrap(), vec![account]);
+
+        let first = block(0, BlockHash::ZERO);
+        let second = block(1, *first.hash());
+        let third = block(2, *second.hash());
+        for block in [&first, &second, &third, &second] {
+            storage.put_block(block).unwrap();
+        }
+        storage.remove_block(third.hash()).unwrap();
+        assert_eq!(
+            storage.block_hashes().unwrap(),
+            vec![*first.hash(), *second.hash()]
+        );
+        assert_eq!(storage.block(second.hash()).unwrap(), Some(second.clone()));
+        assert_eq!(storage.block(third.hash()).unwrap(), None);
+
+        storage.put_work(first.hash(), 2).unwrap();
+        storage.put_work(second.hash(), 4).unwrap();
+        storage.remove_work(first.hash()).unwrap();
+        assert_eq!(storage.work(first.hash()).unwrap(), None);
+        assert_eq!(storage.work(second.hash()).unwrap(), Some(4));
+
+        assert_eq!(storage.base().unwrap(), None);
+        storage.put_base(1).unwrap();
+        storage.put_active_hash(1, second.hash()).unwrap();
+        storage.put_active_hash(2, third.hash()).unwrap();
+        storage.remove_active_hash(2).unwrap();
+        assert_eq!(storage.base().unwrap(), Some(1));
+        assert_eq!(storage.active_hash(1).unwrap(), Some(*second.hash()));
+        assert_eq!(storage.active_hash(2).unwrap(), None);
+
+        let coinbase = second.transactions()[0].id();
+        storage.put_transaction(&coinbase, 1, 0).unwrap();
+        storage
+            .put_transaction(&third.transactions()[0].id(), 2, 0)
+            .unwrap();
+        storage
+            .remove_transaction(&third.transactions()[0].id())
+            .unwrap();
+        assert_eq!(storage.transaction(&coinbase).unwrap(), Some((1, 0)));
+        assert_eq!(
+            storage.transaction(&third.transactions()[0].id()).unwrap(),
+            None
+        );
+
+        let undo = vec![(a, None), (b, Some(Account::new(b)))];
+        storage.put_undo(second.hash(), undo.clone()).unwrap();
+        storage.put_undo(first.hash(), StateUndo::new()).unwrap();
+        storage.remove_undo(first.hash()).unwrap();
+        assert_eq!(storage.undo(second.hash()).unwrap(), Some(undo));
+        assert_eq!(storage.undo(first.hash()).unwrap(), None);
+
+        assert_eq!(storage.params().unwrap(), None);
+        let params = ConsensusParams {
+            min_fee: 7,
+            ..ConsensusParams::default()
+        };
+        storage.put_params(params).unwrap();
+        assert_eq!(storage.params().unwrap(), Some(params));
+
+        storage.commit(second.hash()).unwrap();
+        assert_eq!(storage.committed_tip(), Some(*second.hash()));
+        assert_eq!(storage.account(&a).unwrap(), Some(account));
+        assert_eq!(storage.params().unwrap(), Some(params));
+        assert_eq!(storage.work(second.hash()).unwrap(), Some(4));
+        assert_eq!(storage.active_hash(1).unwrap(), Some(*second.hash()));
+        assert_eq!(storage.base().unwrap(), Some(1));
+        assert_eq!(storage.transaction(&coinbase).unwrap(), Some((1, 0)));
+    }
+
+    #[test]
+    fn memory_storage_behaves_as_storage() {
+        exercise(MemoryStorage::new());
+    }
+
+    #[test]
+    fn file_storage_behaves_as_storage() {
+        let dir = tempfile::tempdir().unwrap();
+        exercise(FileStorage::open(dir.path()).unwrap());
+    }
+}
@@ -4,26 +4,58 @@ use thiserror::Error;
 
 use crate::account::Address;
 
-use super::Transaction;
+use super::TransactionId;
 
 #[derive(Error, Debug)]
 pub enum TransactionError {
     #[error(
-        "VerificationError: failed to verify signature for transaction {transaction:?} : {source}"
+        "VerificationError: failed to verify signature for transaction {transaction} : {source}"
     )]
     VerificationError {
-        transaction: Transaction,
+        transaction: TransactionId,
         #[source]
         source: VerificationError,
     },
+    #[error(
+        "AddressKeyMismatch: public key does not belong to address {address:?} in transaction: {transaction}"
+    )]
+    AddressKeyMismatch {
+        address: Address,
+        transaction: TransactionId,
+    },
+    #[error(
+        "MissingPublicKey: address {address:?} has no public key on chain for transaction: {transaction}"
+    )]
+    MissingPublicKey {
+        address: Address,
+        transaction: TransactionId,
+    },
     #[error("SignatureBadLength: {0}")]
     SignatureBadLength(#[from] SignatureError),
 
     #[error(
-        "InsufficientBalance: address {address:?} has insufficient funds for transaction: {transaction:?}"
+        "InsufficientBalance: address {address:?} has insufficient funds for transaction: {transaction}"
     )]
     InsufficientBalance {
         address: Address,
-        transaction: Transaction,
+        transaction: TransactionId,
+    },
+    #[error("InvalidNonce: expected: {expected}, got: {got} in transaction: {transaction}")]
+    InvalidNonce {
+        expected: u64,
+        got: u64,
+        transaction: TransactionId,
     },
+    #[error("Overflow: balance arithmetic overflowed for transaction {0}")]
+    Overflow(TransactionId),
+    #[error("FeeTooLow: minimum: {minimum}, got: {got}")]
+    FeeTooLow { minimum: u64, got: u64 },
+}
+
+#[derive(Error, Debug)]
+pub enum TransactionIdParseError {
+    #[error("Hex decoding error: {0}")]
+    Hex(hex::FromHexError),
+    #[error("InputLength: Invalid transaction id length (expected 32 bytes)")]
+    InputLength,
 }
@@ -0,0 +1,66 @@
+use std::convert::TryFrom;
+use std::fmt;
+
+use bincode::{Decode, Encode};
+
+use super::error::TransactionIdParseError;
+
+/// SHA3-256 hash of a transaction's canonical bincode encoding.
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug, Encode, Decode)]
+pub struct TransactionId([u8; 32]);
+
+impl From<[u8; 32]> for TransactionId {
+    fn from(value: [u8; 32]) -> Self {
+        Self(value)
+    }
+}
+
+impl TryFrom<&str> for TransactionId {
+    type Error = TransactionIdParseError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let bytes = hex::decode(value).map_err(TransactionIdParseError::Hex)?;
+        let array: [u8; 32] = bytes
+            .try_into()
+            .map_err(|_| TransactionIdParseError::InputLength)?;
+        Ok(Self(array))
+    }
+}
+
+impl fmt::Display for TransactionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl AsRef<[u8]> for TransactionId {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_parses_back() {
+        let id = TransactionId::from([0xab; 32]);
+        let text = id.to_string();
+
+        assert_eq!(text, "ab".repeat(32));
+        assert_eq!(TransactionId::try_from(text.as_str()).unwrap(), id);
+    }
+
+    #[test]
+    fn malformed_ids_are_rejected() {
+        assert!(matches!(
+            TransactionId::try_from("zz"),
+            Err(TransactionIdParseError::Hex(_))
+        ));
+        assert!(matches!(
+            TransactionId::try_from("abcd"),
+            Err(TransactionIdParseError::InputLength)
+        ));
+    }
+}
@@ -1,5 +1,10 @@
 mod error;
+mod id;
 mod transaction;
 
-pub use error::TransactionError;
-pub use transaction::{Transaction, TransactionType, sign, verify_signature};
+pub use error::{TransactionError, TransactionIdParseError};
+pub use id::TransactionId;
+pub use transaction::{
+    ChainId, DEFAULT_CHAIN_ID, SIGNING_DOMAIN, Transaction, TransactionType, sign,
+    verify_signature,
+};
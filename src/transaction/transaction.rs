@@ -3,13 +3,20 @@ use core::fmt;
 use crate::account;
 use bincode::{Decode, Encode};
 use pqcrypto::{
-    sign::falcon512::{self, SecretKey, verify_detached_signature},
-    traits::sign::{DetachedSignature, PublicKey, SignedMessage},
+    sign::falconpadded512::{self, SecretKey, verify_detached_signature},
+    traits::sign::{DetachedSignature, PublicKey},
 };
+use sha3::{Digest, Sha3_256, digest::FixedOutput};
 
-use super::TransactionError;
+use super::{TransactionError, TransactionId};
 
-pub type Signature = [u8; 752];
+pub type Signature = [u8; 666];
+pub type ChainId = u32;
+
+pub const DEFAULT_CHAIN_ID: ChainId = 1;
+
+/// Domain-separation tag prefixed to every transaction signing payload.
+pub const SIGNING_DOMAIN: &[u8] = b"lunaria/transaction/v1";
 
 #[derive(PartialEq, Clone, Copy, Debug, Encode, Decode)]
 #[repr(u8)]
@@ -27,59 +34,133 @@ impl TransactionType {
 #[derive(PartialEq, Clone, Copy, Debug, Encode, Decode)]
 pub struct Transaction {
     pub tx_type: TransactionType,
+    pub nonce: u64,
     pub from_address: account::Address,
-    pub from_public_key: account::PublicKey,
+    pub from_public_key: Option<account::PublicKey>,
     pub signature: Signature,
     pub to_address: account::Address,
     pub amount: u64,
+    /// Paid by the sender on top of `amount` to the producer of the block
+    /// including the transaction.
+    pub fee: u64,
+}
+
+impl Transaction {
+    /// Builds an unsigned transfer from the account owning `from_public_key`.
+    ///
+    /// The public key can be dropped with [`Transaction::without_public_key`]
+    /// once the sender's account has revealed it on chain.
+    pub fn transfer(
+        from_public_key: account::PublicKey,
+        to_address: account::Address,
+        amount: u64,
+        fee: u64,
+        nonce: u64,
+    ) -> Self {
+        Transaction {
+            tx_type: TransactionType::Transfer,
+            nonce,
+            from_address: account::Address::from(from_public_key),
+            from_public_key: Some(from_public_key),
+            signature: [0u8; 666],
+            to_address,
+            amount,
+            fee,
+        }
+    }
+
+    /// Builds a transaction creating `amount` new coins for `to_address`.
+    ///
+    /// Mints are not signed. They only appear in the genesis block and as the
+    /// coinbase of later blocks, where `nonce` is the block index so that
+    /// every coinbase has a distinct ID. They carry no fee.
+    pub fn mint(to_address: account::Address, amount: u64, nonce: u64) -> Self {
+        Transaction {
+            tx_type: TransactionType::Mint,
+            nonce,
+            from_address: account::Address::from([0u8; 32]),
+            from_public_key: None,
+            signature: [0u8; 666],
+            to_address,
+            amount,
+            fee: 0,
+        }
+    }
+
+    pub fn without_public_key(mut self) -> Self {
+        self.from_public_key = None;
+        self
+    }
+
+    pub fn id(&self) -> TransactionId {
+        let encoded = bincode::encode_to_vec(self, bincode::config::standard())
+            .expect("Transaction encoding cannot fail");
+
+        let mut hasher = Sha3_256::new();
+        hasher.update(&encoded);
+        TransactionId::from(<[u8; 32]>::from(hasher.finalize_fixed()))
+    }
+
+    /// Canonical message signed by the sender.
+    ///
+    /// Covers every field except the signature itself, prefixed with
+    /// [`SIGNING_DOMAIN`] and the chain identifier so that a signature is only
+    /// valid for transactions on one network.
+    pub fn signing_payload(&self, chain_id: ChainId) -> Vec<u8> {
+        let mut msg: Vec<u8> = Vec::new();
+
+        msg.extend(SIGNING_DOMAIN);
+        msg.extend(chain_id.to_be_bytes());
+        msg.extend(self.tx_type.to_bytes());
+        msg.extend(self.nonce.to_be_bytes());
+        msg.extend(self.from_address.as_ref());
+        match self.from_public_key {
+            Some(public_key) => {
+                msg.push(1);
+                msg.extend(public_key);
+            }
+            None => msg.push(0),
+        }
+        msg.extend(self.to_address.as_ref());
+        msg.extend(self.amount.to_be_bytes());
+        msg.extend(self.fee.to_be_bytes());
+
+        msg
+    }
 }
 
+/// Signs `transaction` for `chain_id`, replacing any existing signature.
 pub fn sign(
-    tx_type: TransactionType,
-    from_address: account::Address,
-    from_public_key: account::PublicKey,
-    to_address: account::Address,
-    amount: u64,
+    mut transaction: Transaction,
+    chain_id: ChainId,
     secret_key: &SecretKey,
 ) -> Transaction {
-    let mut msg: Vec<u8> = Vec::new();
-
-    msg.extend(tx_type.to_bytes());
-    msg.extend(from_address.as_ref());
-    msg.extend(from_address.as_ref());
-    msg.extend(from_public_key);
-    msg.extend(to_address.as_ref());
-    msg.extend(amount.to_le_bytes());
-
-    let sig = pqcrypto::sign::falcon512::sign(&msg, secret_key);
-
-    Transaction {
-        tx_type,
-        signature: sig
-            .as_bytes()
-            .try_into()
-            .expect("Transaction signature was not 752 bytes"),
-        from_address,
-        from_public_key,
-        to_address,
-        amount,
-    }
-}
+    let msg = transaction.signing_payload(chain_id);
+    let sig = falconpadded512::detached_sign(&msg, secret_key);
 
-pub fn verify_signature(t: &Transaction) -> Result<(), TransactionError> {
-    let mut msg: Vec<u8> = Vec::new();
+    transaction.signature = sig
+        .as_bytes()
+        .try_into()
+        .expect("Transaction signature was not 666 bytes");
 
-    msg.extend(t.from_address.as_ref());
-    msg.extend(&t.from_public_key);
-    msg.extend(t.to_address.as_ref());
-    msg.extend(t.amount.to_le_bytes());
+    transaction
+}
+
+/// Verifies the signature of `t` under `public_key`, which the caller resolves
+/// from the transaction or from the sender's on-chain account.
+pub fn verify_signature(
+    t: &Transaction,
+    public_key: &account::PublicKey,
+    chain_id: ChainId,
+) -> Result<(), TransactionError> {
+    let msg = t.signing_payload(chain_id);
 
-    let sig = falcon512::DetachedSignature::from_bytes(&t.signature)?;
-    let pk = falcon512::PublicKey::from_bytes(&t.from_public_key)?;
+    let sig = falconpadded512::DetachedSignature::from_bytes(&t.signature)?;
+    let pk = falconpadded512::PublicKey::from_bytes(public_key)?;
 
     if let Err(e) = verify_detached_signature(&sig, &msg, &pk) {
         return Err(TransactionError::VerificationError {
-            transaction: t.clone(),
+            transaction: t.id(),
             source: e,
         });
     }
@@ -92,16 +173,102 @@ impl fmt::Display for Transaction {
         write!(
             f,
             "Transaction:\n\
+            \tID                  : {}\n\
             \tType                : {:?}\n\
+            \tNonce               : {}\n\
             \tFrom address        : {}\n\
             \tTo address          : {}\n\
-            \tAmount              : {}",
+            \tAmount              : {}\n\
+            \tFee                 : {}",
+            self.id(),
             self.tx_type,
+            self.nonce,
             self.from_address,
             // hex::encode(self.from_public_key),
             // hex::encode(self.signature),
             self.to_address,
             self.amount,
+            self.fee,
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ledger::testing::Key;
+
+    fn assert_unverified(t: &Transaction, key: &Key, chain_id: ChainId) {
+        match verify_signature(t, &key.public_key, chain_id) {
+            Err(TransactionError::VerificationError { transaction, .. }) => {
+                assert_eq!(transaction, t.id())
+            }
+            other => panic!("expected a verification error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn signed_transfer_verifies() {
+        let key = Key::new();
+        let t = key.transfer(account::Address::from([7u8; 32]), 100, 1, 0);
+
+        verify_signature(&t, &key.public_key, DEFAULT_CHAIN_ID).unwrap();
+        verify_signature(&t.without_public_key(), &key.public_key, DEFAULT_CHAIN_ID).unwrap_err();
+
+        let t = key.sign(t.without_public_key());
+        verify_signature(&t, &key.public_key, DEFAULT_CHAIN_ID).unwrap();
+    }
+
+    #[test]
+    fn signature_is_bound_to_the_chain() {
+        let key = Key::new();
+        let t = key.transfer(account::Address::from([7u8; 32]), 100, 1, 0);
+
+        assert!(
+            t.signing_payload(DEFAULT_CHAIN_ID)
+                .starts_with(SIGNING_DOMAIN)
+        );
+        assert_ne!(
+            t.signing_payload(DEFAULT_CHAIN_ID),
+            t.signing_payload(DEFAULT_CHAIN_ID + 1)
+        );
+        assert_unverified(&t, &key, DEFAULT_CHAIN_ID + 1);
+
+        let t = sign(t, DEFAULT_CHAIN_ID + 1, &key.secret_key);
+        verify_signature(&t, &key.public_key, DEFAULT_CHAIN_ID + 1).unwrap();
+        assert_unverified(&t, &key, DEFAULT_CHAIN_ID);
+    }
+
+    #[test]
+    fn signature_covers_every_field() {
+        let key = Key::new();
+        let t = key.transfer(account::Address::from([7u8; 32]), 100, 1, 0);
+
+        let tampers: [fn(&mut Transaction); 7] = [
+            |t| t.tx_type = TransactionType::Mint,
+            |t| t.nonce += 1,
+            |t| t.fee += 1,
+            |t| t.amount += 1,
+            |t| t.to_address = account::Address::from([8u8; 32]),
+            |t| t.from_public_key = None,
+            |t| t.signature[0] ^= 1,
+        ];
+        for (i, tamper) in tampers.iter().enumerate() {
+            let mut forged = t;
+            tamper(&mut forged);
+            assert_ne!(forged.id(), t.id(), "tamper {i}");
+            assert!(
+                verify_signature(&forged, &key.public_key, DEFAULT_CHAIN_ID).is_err(),
+                "tamper {i} went unnoticed"
+            );
+        }
+
+        // Revealing a key the signer left out is a change as well.
+        let bare = key.sign(t.without_public_key());
+        let revealed = Transaction {
+            from_public_key: Some(key.public_key),
+            ..bare
+        };
+        assert_unverified(&revealed, &key, DEFAULT_CHAIN_ID);
+    }
+}